@@ -4,6 +4,7 @@ use std::ffi::c_char;
 use std::path::PathBuf;
 
 mod llama_context;
+mod llama_context_state;
 mod llama_context_config;
 mod llama_error;
 mod llama_sample_params;
@@ -43,6 +44,12 @@ pub struct LSampleParams {
 /// A context contains the loaded model
 pub struct LContext {
     steps: usize,
+    /// Number of tokens in the KV cache that the next evaluation continues from
+    n_past: usize,
+    /// The tokens in the KV cache, in the order they were evaluated in
+    evaluated_tokens: Vec<llama_cpp_sys::llama_token>,
+    /// Number of prompt tokens the last `load_prompt` found in the KV cache already
+    reused_tokens: usize,
     model: *mut llama_cpp_sys::llama_model,
     pub(crate) ctx: *mut llama_cpp_sys::llama_context,
 
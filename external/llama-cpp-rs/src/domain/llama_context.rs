@@ -1,24 +1,35 @@
 use crate::domain::LTokenSequence;
 use crate::{LContext, LContextConfig, LError, LSampleParams, LToken};
 use llama_cpp_sys::{
-    llama_backend_free, llama_context, llama_free, llama_free_model, llama_get_kv_cache_token_count, llama_get_logits, llama_load_model_from_file,
-    llama_n_ctx, llama_n_vocab, llama_new_context_with_model, llama_sample_repetition_penalty, llama_sample_tail_free, llama_sample_temperature,
-    llama_sample_token, llama_sample_top_k, llama_sample_top_p, llama_sample_typical, llama_token_data, llama_token_data_array, llama_tokenize,
+    llama_backend_free, llama_context, llama_free, llama_free_model, llama_get_logits, llama_load_model_from_file, llama_n_ctx, llama_n_vocab,
+    llama_new_context_with_model, llama_reset_timings, llama_sample_repetition_penalty, llama_sample_tail_free, llama_sample_temperature,
+    llama_sample_token, llama_sample_top_k, llama_sample_top_p, llama_sample_typical, llama_set_rng_seed, llama_token_data, llama_token_data_array,
+    llama_tokenize,
 };
 use std::ffi::CString;
 
 impl LContext {
     pub fn new(mut config: LContextConfig) -> Result<LContext, LError> {
-        let model_path = config.model_path.to_string_lossy();
-        let model_path_c = CString::new(model_path.as_ref())?;
+        let model_path = config.model_path.to_string_lossy().into_owned();
+        let model_path_c = CString::new(model_path.as_str())?;
         let context = unsafe {
             let params = config.native_ptr();
             let model = llama_load_model_from_file(model_path_c.as_ptr(), params);
+            if model.is_null() {
+                return Err(LError::ModelLoadError(format!("failed to load model from {}", model_path)));
+            }
             let ctx = llama_new_context_with_model(model, params);
+            if ctx.is_null() {
+                llama_free_model(model);
+                return Err(LError::ModelLoadError(format!("failed to create context for model {}", model_path)));
+            }
             LContext {
                 model,
                 ctx,
                 steps: 0,
+                n_past: 0,
+                evaluated_tokens: Vec::new(),
+                reused_tokens: 0,
                 candidates: Vec::new(),
                 token_history: Vec::new(),
                 token_buffer: vec![0; 2048],
@@ -29,6 +40,16 @@ impl LContext {
 
     /// Convert a string into a token sequence object.
     pub fn tokenize(&self, value: &str) -> Result<LTokenSequence, LError> {
+        self.tokenize_internal(value, true)
+    }
+
+    /// Convert a string that continues already evaluated tokens into a token sequence object, which
+    /// unlike `tokenize` doesn't start with a beginning of stream token.
+    pub fn tokenize_continuation(&self, value: &str) -> Result<LTokenSequence, LError> {
+        self.tokenize_internal(value, false)
+    }
+
+    fn tokenize_internal(&self, value: &str, add_bos: bool) -> Result<LTokenSequence, LError> {
         let mut tokens = LTokenSequence::new();
 
         // We need to allocate enough space for the entire value to fit into the token space.
@@ -42,7 +63,7 @@ impl LContext {
             let value_c = CString::new(value)?;
             let tokens_buffer_len = tokens.len() as i32;
             let tokens_buffer_ptr = tokens.native_mut_ptr();
-            let token_count = llama_tokenize(ctx, value_c.as_ptr(), tokens_buffer_ptr, tokens_buffer_len, true);
+            let token_count = llama_tokenize(ctx, value_c.as_ptr(), tokens_buffer_ptr, tokens_buffer_len, add_bos);
             if token_count < 0 {
                 return Err(LError::TokenizationError(format!(
                     "failed to tokenize string; context returned {} tokens for a string of length {}",
@@ -58,16 +79,77 @@ impl LContext {
         Ok(tokens)
     }
 
-    /// Load a sequence of tokens into the context
+    /// Forget everything evaluated and sampled so far, so the next prompt starts from a clean state
+    /// without reloading the model. The KV cache is overwritten from the start by the next evaluation.
+    pub fn reset(&mut self) {
+        self.reset_sampling();
+        self.n_past = 0;
+        self.evaluated_tokens.clear();
+    }
+
+    /// Forget the tokens sampled so far, which the repetition penalty looks at, and restart the timings.
+    /// Unlike `reset` this keeps track of the KV cache, so that `load_prompt` can reuse what's in it.
+    pub fn reset_sampling(&mut self) {
+        self.steps = 0;
+        self.token_history.clear();
+        unsafe {
+            llama_reset_timings(self.ctx);
+        }
+    }
+
+    /// Reseed the random number generator used for sampling.
+    pub fn set_seed(&mut self, seed: u32) {
+        unsafe {
+            llama_set_rng_seed(self.ctx, seed);
+        }
+    }
+
+    /// Number of tokens evaluated since the last reset.
+    pub fn n_past(&self) -> usize {
+        self.n_past
+    }
+
+    /// The tokens in the KV cache, in the order they were evaluated in.
+    pub fn evaluated_tokens(&self) -> LTokenSequence {
+        let mut tokens = LTokenSequence::new();
+        for token in &self.evaluated_tokens {
+            tokens.push(LToken::from(*token));
+        }
+        tokens
+    }
+
+    /// Number of tokens at the start of the prompt given to the last `load_prompt` that were in the
+    /// KV cache already, and weren't evaluated again.
+    pub fn reused_tokens(&self) -> usize {
+        self.reused_tokens
+    }
+
+    /// Load a sequence of tokens into the context. The tokens it shares with the start of what was
+    /// evaluated before are kept in the KV cache, only the tokens after them are evaluated.
     pub fn load_prompt(&mut self, prompt: &LTokenSequence, num_threads: usize) -> Result<(), LError> {
         self.steps = 0;
-        self.step(prompt, num_threads)
+
+        // the last token is evaluated in any case, sampling needs the logits it produces
+        let prompt_tokens = unsafe { prompt.native_ptr_slice() };
+        let shared_len = self.evaluated_tokens.iter().zip(prompt_tokens).take_while(|(evaluated, token)| evaluated == token).count();
+        let reused_len = shared_len.min(prompt_tokens.len().saturating_sub(1));
+
+        // evaluating from an earlier position overwrites the rest of the KV cache
+        self.n_past = reused_len;
+        self.evaluated_tokens.truncate(reused_len);
+        self.reused_tokens = reused_len;
+
+        let mut remaining = LTokenSequence::new();
+        for token in &prompt_tokens[reused_len..] {
+            remaining.push(LToken::from(*token));
+        }
+        self.step(&remaining, num_threads)
     }
 
     /// Step the model, generating a single new token given the new input tokens from input.
     pub fn step(&mut self, input: &LTokenSequence, num_threads: usize) -> Result<(), LError> {
         let eval_result = unsafe {
-            let existing_token_count = llama_get_kv_cache_token_count(self.native_ptr());
+            let existing_token_count = self.n_past as i32;
             let input_tokens = input.native_ptr();
             let input_token_count = input.len();
             let max_length = llama_n_ctx(self.native_ptr());
@@ -89,6 +171,8 @@ impl LContext {
             return Err(LError::ApiError(format!("eval returned error code {}", eval_result)));
         }
         self.steps += 1;
+        self.n_past += input.len();
+        self.evaluated_tokens.extend_from_slice(unsafe { input.native_ptr_slice() });
         Ok(())
     }
 
@@ -151,6 +235,10 @@ impl LContext {
     }
 }
 
+// llama.cpp contexts aren't tied to the thread that created them, and `LContext` isn't `Sync`,
+// so it is never used from two threads at once.
+unsafe impl Send for LContext {}
+
 impl Drop for LContext {
     fn drop(&mut self) {
         unsafe {
@@ -0,0 +1,156 @@
+use crate::{LContext, LError};
+use llama_cpp_sys::{llama_copy_state_data, llama_get_state_size, llama_n_ctx, llama_n_embd, llama_n_vocab, llama_set_state_data, llama_token};
+use std::fs;
+use std::path::Path;
+
+/// Marks the start of a saved context state, followed by the format version.
+const STATE_MAGIC: &[u8; 4] = b"LCST";
+const STATE_VERSION: u32 = 2;
+
+impl LContext {
+    /// Serialize the evaluated tokens, the token history and the llama.cpp state of the context, which
+    /// holds the KV cache, the logits of the last evaluation and the random number generator.
+    pub fn state_bytes(&self) -> Vec<u8> {
+        let native_state = unsafe {
+            let mut native_state = vec![0u8; llama_get_state_size(self.ctx)];
+            let written = llama_copy_state_data(self.ctx, native_state.as_mut_ptr());
+            native_state.truncate(written);
+            native_state
+        };
+
+        let mut bytes = Vec::with_capacity(native_state.len() + 4 * (self.evaluated_tokens.len() + self.token_history.len()) + 32);
+        bytes.extend_from_slice(STATE_MAGIC);
+        bytes.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        unsafe {
+            bytes.extend_from_slice(&(llama_n_vocab(self.ctx) as u32).to_le_bytes());
+            bytes.extend_from_slice(&(llama_n_embd(self.ctx) as u32).to_le_bytes());
+            bytes.extend_from_slice(&(llama_n_ctx(self.ctx) as u32).to_le_bytes());
+            bytes.extend_from_slice(&(llama_get_state_size(self.ctx) as u64).to_le_bytes());
+        }
+        write_tokens(&mut bytes, &self.evaluated_tokens);
+        write_tokens(&mut bytes, &self.token_history);
+        bytes.extend_from_slice(&(native_state.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&native_state);
+        bytes
+    }
+
+    /// Restore a state serialized by `state_bytes`, which must come from a context of the same model
+    /// and context size. Sampling can continue right away, as if the evaluated tokens were just loaded.
+    ///
+    /// A state is rejected before it reaches llama.cpp when its vocabulary size, embedding size, context
+    /// size or llama.cpp state size differ from this context, the last of which covers the number of
+    /// layers through the size of the KV cache. A different model of exactly the same shape can't be
+    /// told apart: its state loads, but what is sampled from it is meaningless.
+    pub fn load_state_bytes(&mut self, bytes: &[u8]) -> Result<(), LError> {
+        let mut reader = StateReader { bytes };
+        if reader.take(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err(LError::StateError("not a saved context state".to_string()));
+        }
+        let version = reader.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(LError::StateError(format!("unsupported state version {}, expected {}", version, STATE_VERSION)));
+        }
+
+        let (n_vocab, n_embd, n_ctx, state_size) = unsafe {
+            (
+                llama_n_vocab(self.ctx) as u32,
+                llama_n_embd(self.ctx) as u32,
+                llama_n_ctx(self.ctx) as u32,
+                llama_get_state_size(self.ctx),
+            )
+        };
+        let (saved_n_vocab, saved_n_embd, saved_n_ctx) = (reader.read_u32()?, reader.read_u32()?, reader.read_u32()?);
+        if (saved_n_vocab, saved_n_embd, saved_n_ctx) != (n_vocab, n_embd, n_ctx) {
+            return Err(LError::StateError(format!(
+                "the state was saved with a vocabulary of {}, an embedding size of {} and a context of {} tokens, this context has {}, {} and {}",
+                saved_n_vocab, saved_n_embd, saved_n_ctx, n_vocab, n_embd, n_ctx
+            )));
+        }
+        // llama.cpp asserts that the KV cache of the state matches its own, which would abort the process
+        let saved_state_size = reader.read_u64()?;
+        if saved_state_size != state_size as u64 {
+            return Err(LError::StateError(format!(
+                "the state was saved from a llama.cpp state of {} bytes, this context has {} bytes, it belongs to a different model",
+                saved_state_size, state_size
+            )));
+        }
+
+        let evaluated_tokens = reader.read_tokens()?;
+        let token_history = reader.read_tokens()?;
+        if evaluated_tokens.len() >= n_ctx as usize {
+            return Err(LError::StateError(format!("{} evaluated tokens don't fit a context of {} tokens", evaluated_tokens.len(), n_ctx)));
+        }
+        let native_len = reader.read_u64()? as usize;
+        let native_state = reader.take(native_len)?;
+        if native_len > state_size {
+            return Err(LError::StateError(format!("the llama.cpp state is {} bytes, more than the {} bytes of this context", native_len, state_size)));
+        }
+
+        // llama.cpp trusts the sizes stored in the data, so it is read from a buffer of the full
+        // state size, which a corrupted state can't make it read past
+        let mut native_buffer = vec![0u8; state_size];
+        native_buffer[..native_len].copy_from_slice(native_state);
+        unsafe {
+            llama_set_state_data(self.ctx, native_buffer.as_mut_ptr());
+        }
+
+        // the logits of the last evaluated token are part of the state, so they can be sampled from
+        self.steps = if evaluated_tokens.is_empty() { 0 } else { 1 };
+        self.n_past = evaluated_tokens.len();
+        self.evaluated_tokens = evaluated_tokens;
+        self.token_history = token_history;
+        self.reused_tokens = 0;
+        Ok(())
+    }
+
+    /// Write the state of the context to a file, see `state_bytes`.
+    pub fn save_state(&self, path: impl AsRef<Path>) -> Result<(), LError> {
+        let path = path.as_ref();
+        let bytes = self.state_bytes();
+        fs::write(path, bytes).map_err(|err| LError::StateError(format!("failed to write state to {}: {}", path.display(), err)))
+    }
+
+    /// Restore the state of the context from a file written by `save_state`, see `load_state_bytes`.
+    pub fn load_state(&mut self, path: impl AsRef<Path>) -> Result<(), LError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|err| LError::StateError(format!("failed to read state from {}: {}", path.display(), err)))?;
+        self.load_state_bytes(&bytes)
+    }
+}
+
+fn write_tokens(bytes: &mut Vec<u8>, tokens: &[llama_token]) {
+    bytes.extend_from_slice(&(tokens.len() as u32).to_le_bytes());
+    for token in tokens {
+        bytes.extend_from_slice(&token.to_le_bytes());
+    }
+}
+
+/// Reads the fields of a saved state one after the other, failing on truncated data.
+struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LError> {
+        if self.bytes.len() < len {
+            return Err(LError::StateError("the saved state is truncated".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, LError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, LError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_tokens(&mut self) -> Result<Vec<llama_token>, LError> {
+        let len = self.read_u32()? as usize;
+        let bytes = self.take(len.checked_mul(4).ok_or_else(|| LError::StateError("the saved state is truncated".to_string()))?)?;
+        Ok(bytes.chunks_exact(4).map(|chunk| llama_token::from_le_bytes(chunk.try_into().unwrap())).collect())
+    }
+}
@@ -17,6 +17,16 @@ pub enum LError {
 
     /// If you try to do something that will not fix in the buffer you've allocated.
     OutOfBufferSpace(String),
+
+    /// The model file could not be loaded, or a context could not be created for it.
+    ModelLoadError(String),
+
+    /// A generation was asked to continue the previous one, but the context doesn't hold a
+    /// finished run to continue from.
+    NothingToContinue,
+
+    /// A context state couldn't be saved or read, or was saved from a context whose model or context size has a different shape.
+    StateError(String),
 }
 
 impl Error for LError {}
@@ -1,4 +1,5 @@
-use crate::{LContext, LError, LSampleParams, LTokenSequence};
+use crate::{LContext, LError, LSampleParams, LToken, LTokenSequence};
+use std::time::{Duration, Instant};
 
 pub struct LGeneratorParams {
     /// Generate this number of tokens before halting
@@ -9,32 +10,154 @@ pub struct LGeneratorParams {
 
     /// Settings to use for sampling the model
     pub sample_params: LSampleParams,
+
+    /// Halt as soon as the output contains any of these strings. The stop string and anything after it
+    /// are left out of the output, and text that could be the start of one is only handed to the
+    /// callback once it's clear it isn't.
+    pub stop_sequences: Vec<String>,
+}
+
+/// Why a generation run ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LStopReason {
+    /// The model generated its end of stream token
+    EndOfStream,
+
+    /// `generate_tokens` tokens were generated
+    TokenLimit,
+
+    /// The incremental callback returned false
+    Halted,
+
+    /// The output contained one of `stop_sequences`
+    StopSequence,
+
+    /// The context has no space left for another token
+    ContextFull,
+}
+
+/// The result of a generation run
+#[derive(Clone, Debug)]
+pub struct LGeneration {
+    pub text: String,
+
+    /// Everything the generated tokens decode to, which is what the context holds after the prompt. Unlike
+    /// `text` this includes a stop sequence and whatever came after it in the same token.
+    pub context_text: String,
+
+    pub stop_reason: LStopReason,
+}
+
+/// Timings and token counts of the last generation run
+#[derive(Clone, Debug, Default)]
+pub struct LGenerationStats {
+    /// Number of tokens the prompt was split into
+    pub prompt_tokens: usize,
+
+    /// Number of prompt tokens that were in the context already from an earlier run, and weren't evaluated again
+    pub reused_prompt_tokens: usize,
+
+    /// Number of tokens sampled from the model, not counting the end of stream token
+    pub generated_tokens: usize,
+
+    /// Time spent tokenizing and evaluating the prompt
+    pub prompt_eval_duration: Duration,
+
+    /// Time spent generating tokens after the prompt was evaluated
+    pub generation_duration: Duration,
+
+    /// Time from the start of the run until the first token was handed to the callback, if there was one
+    pub time_to_first_token: Option<Duration>,
 }
 
 pub struct LGenerator {
     context: LContext,
+    stats: LGenerationStats,
+
+    /// Whether the context holds exactly the prompt and output of the last run
+    can_continue: bool,
+
+    /// The last generated token, if it was sampled but not evaluated yet
+    pending_token: Option<LToken>,
 }
 
 impl LGenerator {
     pub fn new(context: LContext) -> LGenerator {
-        LGenerator { context }
+        LGenerator {
+            context,
+            stats: LGenerationStats::default(),
+            can_continue: false,
+            pending_token: None,
+        }
+    }
+
+    /// The context the generator runs on, e.g. to reseed it between runs.
+    pub fn context_mut(&mut self) -> &mut LContext {
+        &mut self.context
+    }
+
+    /// Statistics of the most recent call to one of the generate functions, including runs that were
+    /// halted by the callback or failed part way through.
+    pub fn stats(&self) -> &LGenerationStats {
+        &self.stats
     }
 
     fn generate_no_op(_value: &[String]) -> bool {
         true
     }
 
-    pub fn generate(&mut self, prompt: &str, params: LGeneratorParams) -> Result<String, LError> {
+    pub fn generate(&mut self, prompt: &str, params: LGeneratorParams) -> Result<LGeneration, LError> {
         self.generate_internal(prompt, params, LGenerator::generate_no_op)
     }
 
-    pub fn generate_incremental(&mut self, prompt: &str, params: LGeneratorParams, callback: impl FnMut(&[String]) -> bool) -> Result<String, LError> {
+    pub fn generate_incremental(&mut self, prompt: &str, params: LGeneratorParams, callback: impl FnMut(&[String]) -> bool) -> Result<LGeneration, LError> {
         self.generate_internal(prompt, params, callback)
     }
 
-    pub fn generate_internal(&mut self, prompt: &str, params: LGeneratorParams, mut callback: impl FnMut(&[String]) -> bool) -> Result<String, LError> {
-        // Load prompt
-        let prompt_tokens = self.context.tokenize(prompt)?;
+    pub fn generate_internal(&mut self, prompt: &str, params: LGeneratorParams, callback: impl FnMut(&[String]) -> bool) -> Result<LGeneration, LError> {
+        // Every run samples from a clean slate, only the part of the context that matches the prompt is kept
+        self.context.reset_sampling();
+        self.pending_token = None;
+        self.generate_from(prompt, false, params, callback)
+    }
+
+    /// Whether the last run ended with the end of stream token, the token limit or a stop sequence, so
+    /// that the context holds exactly its prompt and `LGeneration::context_text`, and `generate_continuation`
+    /// can follow it.
+    pub fn can_continue(&self) -> bool {
+        self.can_continue
+    }
+
+    /// Like `generate_incremental`, but `prompt` is appended to the prompt and output of the last run
+    /// instead of replacing them, so only its own tokens are evaluated. This is what keeps a conversation
+    /// going without evaluating all of it again each turn, even when the transcript wouldn't tokenize
+    /// into the tokens that were generated.
+    pub fn generate_continuation(&mut self, prompt: &str, params: LGeneratorParams, callback: impl FnMut(&[String]) -> bool) -> Result<LGeneration, LError> {
+        if !self.can_continue {
+            return Err(LError::NothingToContinue);
+        }
+        self.generate_from(prompt, true, params, callback)
+    }
+
+    fn generate_from(&mut self, prompt: &str, is_continuation: bool, params: LGeneratorParams, mut callback: impl FnMut(&[String]) -> bool) -> Result<LGeneration, LError> {
+        self.stats = LGenerationStats::default();
+        self.can_continue = false;
+        let start = Instant::now();
+
+        // Load prompt, after the tokens of the previous run, the last one of which may not be evaluated yet
+        let prompt_tokens = if is_continuation {
+            let mut prompt_tokens = self.context.evaluated_tokens();
+            if let Some(token) = self.pending_token.take() {
+                prompt_tokens.push(token);
+            }
+            for token in self.context.tokenize_continuation(prompt)?.iter() {
+                prompt_tokens.push(token);
+            }
+            prompt_tokens
+        } else {
+            self.context.tokenize(prompt)?
+        };
+        self.stats.prompt_tokens = prompt_tokens.len();
         let mut token_stream = prompt_tokens;
 
         // The query buffer is a window into the token stream to use for inference
@@ -43,41 +166,203 @@ impl LGenerator {
 
         // Initialize with prompt
         self.context.load_prompt(&token_stream, params.worker_thread_count)?;
+        self.stats.reused_prompt_tokens = self.context.reused_tokens();
+        let prompt_evaluated = Instant::now();
+        self.stats.prompt_eval_duration = prompt_evaluated - start;
 
-        let mut token_strings = Vec::new();
-        for _ in 0..(params.generate_tokens - 1) {
-            gen_buffer.clear();
-            gen_buffer.copy_trailing(&token_stream);
+        let result = self.generate_tokens(&mut token_stream, &mut gen_buffer, &params, &mut callback, start);
+        self.stats.generation_duration = prompt_evaluated.elapsed();
 
-            // Invoke model
-            self.context.step(&gen_buffer, params.worker_thread_count)?;
+        unsafe {
+            llama_cpp_sys::llama_print_timings(self.context.ctx);
+        }
+
+        self.can_continue = matches!(
+            &result,
+            Ok(generation) if matches!(generation.stop_reason, LStopReason::EndOfStream | LStopReason::TokenLimit | LStopReason::StopSequence)
+        );
+        result
+    }
+
+    /// Generate tokens until `generate_tokens` is reached, the model ends the stream, the callback halts
+    /// or the context is full.
+    fn generate_tokens(
+        &mut self,
+        token_stream: &mut LTokenSequence,
+        gen_buffer: &mut LTokenSequence,
+        params: &LGeneratorParams,
+        callback: &mut impl FnMut(&[String]) -> bool,
+        start: Instant,
+    ) -> Result<LGeneration, LError> {
+        // the text handed to the callback, one piece per token unless text was held back for stop sequences
+        let mut token_strings = Vec::new();
+        let mut output = String::new();
+        let mut context_text = String::new();
+        let mut delivered = 0;
+        let mut stop_reason = LStopReason::TokenLimit;
+        for i in 0..params.generate_tokens {
+            // Invoke model on the last sampled token; the prompt was evaluated by load_prompt already.
+            // Running out of space ends the output rather than discarding it
+            if i > 0 {
+                gen_buffer.clear();
+                gen_buffer.copy_trailing(token_stream);
+                match self.context.step(gen_buffer, params.worker_thread_count) {
+                    Err(LError::OutOfBufferSpace(_)) => {
+                        stop_reason = LStopReason::ContextFull;
+                        break;
+                    }
+                    result => result?,
+                }
+                self.pending_token = None;
+            }
 
             // Sample result
             let token = self.context.sample(Some(params.sample_params))?;
             if token.is_end_of_stream(&self.context) {
+                stop_reason = LStopReason::EndOfStream;
                 break;
             }
 
             // Save token
             token_stream.push(token.clone());
+            self.pending_token = Some(token.clone());
+            self.stats.generated_tokens += 1;
 
             // Incremental completion callback
             if token.has_str_value(&self.context) {
                 let token_string = token.as_string(&mut self.context)?;
-                token_strings.push(token_string);
+                self.stats.time_to_first_token.get_or_insert_with(|| start.elapsed());
 
-                // Halt early if the incremental thinks we're done
-                if !callback(&token_strings) {
+                // a stop sequence can only start in the part of the output that wasn't handed out yet
+                context_text += &token_string;
+                output += &token_string;
+                if let Some(stop_index) = find_stop_sequence(&output[delivered..], &params.stop_sequences) {
+                    output.truncate(delivered + stop_index);
+                    if output.len() > delivered {
+                        token_strings.push(output[delivered..].to_string());
+                        delivered = output.len();
+                        callback(&token_strings);
+                    }
+                    stop_reason = LStopReason::StopSequence;
                     break;
                 }
+
+                let safe_end = output.len() - held_back_len(&output[delivered..], &params.stop_sequences);
+                if safe_end > delivered {
+                    token_strings.push(output[delivered..safe_end].to_string());
+                    delivered = safe_end;
+
+                    // Halt early if the incremental thinks we're done
+                    if !callback(&token_strings) {
+                        output.truncate(delivered);
+                        stop_reason = LStopReason::Halted;
+                        break;
+                    }
+                }
             }
         }
 
-        unsafe {
-            llama_cpp_sys::llama_print_timings(self.context.ctx);
+        // text held back for a stop sequence that never came is part of the output after all
+        if output.len() > delivered {
+            token_strings.push(output[delivered..].to_string());
+            callback(&token_strings);
+        }
+
+        Ok(LGeneration { text: output, context_text, stop_reason })
+    }
+}
+
+/// Byte index of the earliest stop sequence in `text`.
+fn find_stop_sequence(text: &str, stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences.iter().filter_map(|stop| text.find(stop.as_str())).min()
+}
+
+/// Length of the longest end of `text` that is the start of a stop sequence, and may turn into one
+/// once more tokens are generated.
+fn held_back_len(text: &str, stop_sequences: &[String]) -> usize {
+    stop_sequences
+        .iter()
+        .filter_map(|stop| {
+            (1..stop.len().min(text.len() + 1))
+                .rev()
+                .find(|&len| text.is_char_boundary(text.len() - len) && stop.starts_with(&text[text.len() - len..]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stops(stops: &[&str]) -> Vec<String> {
+        stops.iter().map(|stop| stop.to_string()).collect()
+    }
+
+    #[test]
+    fn stop_sequence_split_across_pieces() {
+        let stop_sequences = stops(&["</s>"]);
+        let mut output = String::new();
+        let mut held_back = Vec::new();
+        for piece in ["Hello <", "/", "s", "> after"] {
+            output += piece;
+            if let Some(index) = find_stop_sequence(&output, &stop_sequences) {
+                assert_eq!(&output[..index], "Hello ");
+                break;
+            }
+            held_back.push(held_back_len(&output, &stop_sequences));
         }
+        assert_eq!(held_back, [1, 2, 3]);
+    }
+
+    #[test]
+    fn partial_match_that_is_no_stop_sequence() {
+        let stop_sequences = stops(&["</s>"]);
+        assert_eq!(held_back_len("Use <", &stop_sequences), 1);
+        assert_eq!(held_back_len("Use </", &stop_sequences), 2);
+        assert_eq!(find_stop_sequence("Use </b>", &stop_sequences), None);
+        assert_eq!(held_back_len("Use </b>", &stop_sequences), 0);
+        // only the end of the text can still become a stop sequence
+        assert_eq!(held_back_len("</ and more", &stop_sequences), 0);
+    }
+
+    #[test]
+    fn multibyte_characters_at_the_holdback_boundary() {
+        // the stop sequence starts with a character that shares its first two bytes with `€`
+        let stop_sequences = stops(&["₭!"]);
+        assert_eq!(held_back_len("costs 5€", &stop_sequences), 0);
+        assert_eq!(held_back_len("costs 5₭", &stop_sequences), "₭".len());
+        assert_eq!(find_stop_sequence("costs 5₭!", &stop_sequences), Some("costs 5".len()));
+
+        let stop_sequences = stops(&["éclair", "日本語"]);
+        assert_eq!(held_back_len("un é", &stop_sequences), "é".len());
+        assert_eq!(held_back_len("こんにちは日本", &stop_sequences), "日本".len());
+        assert_eq!(held_back_len("日", &stop_sequences), "日".len());
+        assert_eq!(held_back_len("本", &stop_sequences), 0);
+    }
+
+    #[test]
+    fn overlapping_stop_sequences() {
+        // the earliest stop sequence wins, wherever the others are
+        let stop_sequences = stops(&["bc", "ab"]);
+        assert_eq!(find_stop_sequence("xabc", &stop_sequences), Some(1));
+        let stop_sequences = stops(&["User:", "User"]);
+        assert_eq!(find_stop_sequence("Hi\nUser: yes", &stop_sequences), Some(3));
+
+        // the longest possible start of any of them is held back
+        let stop_sequences = stops(&["abc", "bcd"]);
+        assert_eq!(held_back_len("xab", &stop_sequences), 2);
+        assert_eq!(held_back_len("xbc", &stop_sequences), 2);
+        assert_eq!(find_stop_sequence("xabc", &stop_sequences), Some(1));
+        assert_eq!(held_back_len("xabce", &stop_sequences), 0);
+        let stop_sequences = stops(&["aab"]);
+        assert_eq!(held_back_len("aaa", &stop_sequences), 2);
+    }
 
-        // Convert token stream back into a string
-        Ok(token_strings.join(""))
+    #[test]
+    fn nothing_is_held_back_without_stop_sequences() {
+        assert_eq!(find_stop_sequence("anything", &[]), None);
+        assert_eq!(held_back_len("anything", &[]), 0);
+        assert_eq!(held_back_len("", &stops(&["</s>"])), 0);
     }
 }
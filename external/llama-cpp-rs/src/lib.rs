@@ -2,4 +2,4 @@ pub mod domain;
 pub mod generators;
 
 pub use domain::{LContext, LContextConfig, LError, LSampleParams, LToken, LTokenSequence};
-pub use generators::{LGenerator, LGeneratorParams};
+pub use generators::{LGeneration, LGenerationStats, LGenerator, LGeneratorParams, LStopReason};
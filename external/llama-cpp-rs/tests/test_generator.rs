@@ -27,9 +27,10 @@ pub fn main() {
                     repeat_penalty: 1.1f32,
                     ..Default::default()
                 },
+                stop_sequences: Vec::new(),
             },
         )
         .unwrap();
-    assert!(!output.is_empty());
-    println!("{}", output);
+    assert!(!output.text.is_empty());
+    println!("{}", output.text);
 }
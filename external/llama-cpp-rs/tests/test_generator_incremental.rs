@@ -32,6 +32,7 @@ pub fn main() {
                     repeat_penalty: 1f32,
                     ..Default::default()
                 },
+                stop_sequences: Vec::new(),
             },
             |generated| {
                 print!("{}", generated[generated.len() - 1]);
@@ -43,6 +44,6 @@ pub fn main() {
             },
         )
         .unwrap();
-    assert!(!output.is_empty());
-    println!("{}", output);
+    assert!(!output.text.is_empty());
+    println!("{}", output.text);
 }
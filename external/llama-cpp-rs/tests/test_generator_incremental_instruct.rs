@@ -39,6 +39,7 @@ Implement a linked list in rust.
                     repeat_penalty: 1f32,
                     ..Default::default()
                 },
+                stop_sequences: Vec::new(),
             },
             |generated| {
                 print!("{}", generated[generated.len() - 1]);
@@ -50,6 +51,6 @@ Implement a linked list in rust.
             },
         )
         .unwrap();
-    assert!(!output.is_empty());
-    println!("{}", output);
+    assert!(!output.text.is_empty());
+    println!("{}", output.text);
 }
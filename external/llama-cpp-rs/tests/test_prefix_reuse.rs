@@ -0,0 +1,82 @@
+use llama_cpp_rs::{LContext, LContextConfig, LGenerator, LGeneratorParams, LSampleParams, LTokenSequence};
+
+fn config() -> LContextConfig {
+    let mut config = LContextConfig::new("models/model.gguf");
+    config.n_ctx = 512;
+    config.n_gpu_layers = 32;
+    config.seed = 0;
+    config
+}
+
+/// The text of every token, with `None` for the tokens that have none, like the beginning of stream
+fn token_texts(context: &mut LContext, tokens: &LTokenSequence) -> Vec<Option<String>> {
+    tokens
+        .iter()
+        .map(|token| match token.has_str_value(context) {
+            true => Some(token.as_string(context).unwrap()),
+            false => None,
+        })
+        .collect()
+}
+
+fn params() -> LGeneratorParams {
+    LGeneratorParams {
+        worker_thread_count: 8,
+        generate_tokens: 16,
+        sample_params: LSampleParams {
+            top_k: 40,
+            top_p: 0.95f32,
+            temp: 0.8f32,
+            repeat_penalty: 1.1f32,
+            ..Default::default()
+        },
+        stop_sequences: Vec::new(),
+    }
+}
+
+#[test]
+pub fn main() {
+    let sample_worker_threads = 8;
+    let first_prompt = "[INST]bob is a space pilot. Alice is a potato. Tell me about bob.[/INST]";
+    let second_prompt = "[INST]bob is a space pilot. Alice is a potato. Tell me about Alice.[/INST]";
+
+    let mut context = LContext::new(config()).unwrap();
+    let first_tokens = context.tokenize(first_prompt).unwrap();
+    let second_tokens = context.tokenize(second_prompt).unwrap();
+    let first_texts = token_texts(&mut context, &first_tokens);
+    let second_texts = token_texts(&mut context, &second_tokens);
+    let shared_len = first_texts.iter().zip(&second_texts).take_while(|(first, second)| first == second).count();
+    assert!(shared_len > 0 && shared_len < second_tokens.len());
+
+    // Nothing is reused by the first prompt, the second one reuses the tokens it starts with
+    context.load_prompt(&first_tokens, sample_worker_threads).unwrap();
+    assert_eq!(context.reused_tokens(), 0);
+    context.load_prompt(&second_tokens, sample_worker_threads).unwrap();
+    assert_eq!(context.reused_tokens(), shared_len);
+    let evaluated = context.evaluated_tokens();
+    assert_eq!(token_texts(&mut context, &evaluated), second_texts);
+
+    // A strict prefix of what was evaluated still evaluates its last token, sampling needs its logits
+    let mut prefix_tokens = LTokenSequence::new();
+    for token in second_tokens.iter().take(second_tokens.len() - 3) {
+        prefix_tokens.push(token);
+    }
+    context.load_prompt(&prefix_tokens, sample_worker_threads).unwrap();
+    assert_eq!(context.reused_tokens(), prefix_tokens.len() - 1);
+    assert_eq!(context.n_past(), prefix_tokens.len());
+    assert_eq!(context.evaluated_tokens().len(), prefix_tokens.len());
+
+    // Generating after reusing a prefix gives what a fresh context gives for the same seed
+    let mut generator = LGenerator::new(context);
+    generator.generate(first_prompt, params()).unwrap();
+    generator.context_mut().set_seed(7);
+    let reused_output = generator.generate(second_prompt, params()).unwrap();
+    assert_eq!(generator.stats().reused_prompt_tokens, shared_len);
+
+    let mut fresh_generator = LGenerator::new(LContext::new(config()).unwrap());
+    fresh_generator.context_mut().set_seed(7);
+    let fresh_output = fresh_generator.generate(second_prompt, params()).unwrap();
+    assert_eq!(fresh_generator.stats().reused_prompt_tokens, 0);
+    assert_eq!(reused_output.text, fresh_output.text);
+    println!("{}", reused_output.text);
+}
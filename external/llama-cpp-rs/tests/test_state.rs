@@ -0,0 +1,53 @@
+use llama_cpp_rs::{LContext, LContextConfig, LSampleParams};
+
+#[test]
+pub fn main() {
+    let sample_worker_threads = 8;
+
+    // Setup params
+    let mut config = LContextConfig::new("models/model.gguf");
+    config.n_ctx = 512;
+    config.n_gpu_layers = 32;
+    config.seed = 0;
+
+    // Load model and evaluate a prompt
+    let mut context = LContext::new(config).unwrap();
+    let prompt_tokens = context.tokenize("[INST]bob is a space pilot. Alice is a potato.[/INST]").unwrap();
+    context.load_prompt(&prompt_tokens, sample_worker_threads).unwrap();
+
+    // Save the state, both in memory and to a file
+    let state = context.state_bytes();
+    let state_path = std::env::temp_dir().join("llama-cpp-rs-test-state.bin");
+    context.save_state(&state_path).unwrap();
+
+    // Sampling after restoring the state picks the same token, since the random number generator is part of it
+    let sample_params = Some(LSampleParams {
+        temp: 0.8f32,
+        ..Default::default()
+    });
+    let token = context.sample(sample_params).unwrap();
+
+    context.reset();
+    context.load_state_bytes(&state).unwrap();
+    assert_eq!(context.n_past(), prompt_tokens.len());
+    assert_eq!(context.evaluated_tokens().len(), prompt_tokens.len());
+    let restored_token = context.sample(sample_params).unwrap();
+    assert_eq!(token.as_string(&mut context).unwrap(), restored_token.as_string(&mut context).unwrap());
+
+    context.reset();
+    context.load_state(&state_path).unwrap();
+    let restored_token = context.sample(sample_params).unwrap();
+    assert_eq!(token.as_string(&mut context).unwrap(), restored_token.as_string(&mut context).unwrap());
+
+    // A state of a different size, as saved from another model, is rejected before llama.cpp sees it
+    let mut other_state = state.clone();
+    other_state[20..28].copy_from_slice(&(state.len() as u64 * 2).to_le_bytes());
+    assert!(context.load_state_bytes(&other_state).is_err());
+    assert_eq!(context.n_past(), prompt_tokens.len());
+
+    // The restored prompt is reused rather than evaluated again
+    context.load_prompt(&prompt_tokens, sample_worker_threads).unwrap();
+    assert_eq!(context.reused_tokens(), prompt_tokens.len() - 1);
+
+    std::fs::remove_file(state_path).unwrap();
+}
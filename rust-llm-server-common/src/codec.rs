@@ -0,0 +1,269 @@
+//! Encodings a `Message` can be sent in. Every connection uses a single codec, which is the one
+//! the client encoded its `Hello` with.
+
+use std::fmt;
+
+use crate::Message;
+
+#[derive(Debug)]
+pub struct CodecError(pub String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+pub trait Codec: Send + Sync {
+    /// Name of the codec, e.g. for logging
+    fn name(&self) -> &'static str;
+    /// Whether the encoded messages are UTF-8 text, which WebSocket clients get in text frames
+    fn is_text(&self) -> bool {
+        false
+    }
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, data: &[u8]) -> Result<Message, CodecError>;
+}
+
+/// The compact encoding of the `bincode` crate, easiest to use from Rust.
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(message).map_err(|err| CodecError(err.to_string()))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Message, CodecError> {
+        bincode::deserialize(data).map_err(|err| CodecError(err.to_string()))
+    }
+}
+
+/// UTF-8 JSON text. Enum variants are objects with the variant name as their only key,
+/// e.g. `{"CancelGeneration":{"request_id":1}}`.
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn is_text(&self) -> bool {
+        true
+    }
+
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(message).map_err(|err| CodecError(err.to_string()))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Message, CodecError> {
+        serde_json::from_slice(data).map_err(|err| CodecError(err.to_string()))
+    }
+}
+
+/// MessagePack, laid out like the JSON encoding: structs are maps keyed by field name.
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(message).map_err(|err| CodecError(err.to_string()))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Message, CodecError> {
+        rmp_serde::from_slice(data).map_err(|err| CodecError(err.to_string()))
+    }
+}
+
+/// Every supported codec, in the order they are tried when decoding a `Hello`.
+pub const CODECS: [&dyn Codec; 3] = [&BincodeCodec, &JsonCodec, &MessagePackCodec];
+
+/// Find the codec a `Hello` frame was encoded with, returning it along with the decoded message.
+/// Only a codec that decodes the frame into a `Hello` naming that same codec counts, so that a frame
+/// which happens to be valid in another encoding too isn't mistaken for it.
+pub fn decode_hello(data: &[u8]) -> Option<(&'static dyn Codec, Message)> {
+    CODECS.into_iter().find_map(|codec| {
+        let message = codec.decode(data).ok()?;
+        let Message::Hello { codec: name, .. } = &message else {
+            return None;
+        };
+        (name == codec.name()).then_some((codec, message))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatMessage, ChatRole, ErrorCode, GenerationParams, GenerationResults, ModelInfo, ModelStatus, OutputProcessorKind, StopReason};
+
+    fn params() -> GenerationParams {
+        GenerationParams {
+            top_k: Some(40),
+            top_p: Some(0.95),
+            temp: Some(0.8),
+            repeat_penalty: None,
+            repeat_history_length: Some(64),
+            tfs_z: None,
+            typical_p: Some(1.0),
+            max_tokens: Some(128),
+            seed: Some(42),
+            thread_count: None,
+            stop: Some(vec!["</s>".to_string(), "\nUser:".to_string()]),
+            output: Some(vec![
+                OutputProcessorKind::StripMarkdown,
+                OutputProcessorKind::StripChars { chars: "#*".to_string() },
+                OutputProcessorKind::SplitLines,
+                OutputProcessorKind::Raw,
+            ]),
+        }
+    }
+
+    fn results() -> GenerationResults {
+        GenerationResults {
+            stop_reason: StopReason::StopSequence,
+            full_generated_lines: vec!["Hello".to_string(), "wörld ✓".to_string()],
+            full_generated_text: "Hello\nwörld ✓\n".to_string(),
+            feed_prompt_dur_ms: 1234,
+            predict_dur_ms: 5678,
+            time_to_first_token_ms: Some(99),
+            prompt_tokens: 12,
+            reused_prompt_tokens: 3,
+            predict_tokens: 7,
+        }
+    }
+
+    fn model_info(status: ModelStatus) -> ModelInfo {
+        ModelInfo { name: "llama".to_string(), context_size: 2048, status, is_default: true }
+    }
+
+    fn chat() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage { role: ChatRole::System, content: "Be brief.".to_string() },
+            ChatMessage { role: ChatRole::User, content: "Hi!".to_string() },
+            ChatMessage { role: ChatRole::Assistant, content: "Hello.".to_string() },
+        ]
+    }
+
+    /// One message of every variant, with the optional fields both set and left out.
+    fn every_message() -> Vec<Message> {
+        vec![
+            Message::Hello { protocol_version: crate::PROTOCOL_VERSION, capabilities: vec!["streaming".to_string()], codec: "json".to_string() },
+            Message::Welcome { protocol_version: crate::PROTOCOL_VERSION, capabilities: Vec::new(), codec: "bincode".to_string() },
+            Message::HelloRejected { protocol_version: 1, reason: "too old".to_string() },
+            Message::GeneratePrompt { request_id: 1, prompt: "Once upon a time".to_string(), stream: true, params: Some(params()), model: Some("llama".to_string()) },
+            Message::GeneratePrompt { request_id: 2, prompt: String::new(), stream: false, params: None, model: None },
+            Message::GenerateChat { request_id: 3, messages: chat(), stream: false, params: Some(GenerationParams::default()), model: None },
+            Message::RequestCurrentGeneratedLines { request_id: 4 },
+            Message::CancelGeneration { request_id: 5 },
+            Message::ListModels { request_id: 6 },
+            Message::CreateChatSession { request_id: 7, model: Some("llama".to_string()), system_prompt: Some("Be brief.".to_string()) },
+            Message::CreateChatSession { request_id: 8, model: None, system_prompt: None },
+            Message::AppendUserMessage { request_id: 9, session_id: 10, content: "And then?".to_string() },
+            Message::GenerateChatReply { request_id: 11, session_id: 10, stream: true, params: Some(params()) },
+            Message::GetChatHistory { request_id: 12, session_id: 10 },
+            Message::DeleteChatSession { request_id: 13, session_id: 10 },
+            Message::LoadModel {
+                request_id: 14,
+                name: "mistral".to_string(),
+                path: Some("models/mistral.gguf".to_string()),
+                context_size: Some(4096),
+                gpu_layers: Some(-1),
+                chat_template: Some("mistral".to_string()),
+            },
+            Message::LoadModel { request_id: 15, name: "mistral".to_string(), path: None, context_size: None, gpu_layers: None, chat_template: None },
+            Message::UnloadModel { request_id: 16, name: "mistral".to_string(), cancel: true },
+            Message::ReloadModel { request_id: 17, name: "mistral".to_string(), cancel: false },
+            Message::GenerationDone { request_id: 18, results: results() },
+            Message::CurrentGeneratedLinesResponse { request_id: 19, lines: vec!["a".to_string(), String::new()] },
+            Message::GenerationQueued { request_id: 20, position: 3 },
+            Message::QueuePositionChanged { request_id: 21, position: 1 },
+            Message::GenerationStarted { request_id: 22 },
+            Message::TokenGenerated { request_id: 23, token: " wö".to_string() },
+            Message::LineGenerated { request_id: 24, line_index: 2, line: "a line".to_string() },
+            Message::GenerationCancelled { request_id: 25, results: GenerationResults::terminated_before_start() },
+            Message::ModelList {
+                request_id: 26,
+                models: vec![
+                    model_info(ModelStatus::Loading),
+                    model_info(ModelStatus::Loaded),
+                    model_info(ModelStatus::Failed { reason: "no such file".to_string() }),
+                    model_info(ModelStatus::Unloading),
+                    model_info(ModelStatus::Unloaded),
+                ],
+            },
+            Message::ModelLoaded { request_id: 27, model: model_info(ModelStatus::Loaded) },
+            Message::ModelUnloaded { request_id: 28, name: "mistral".to_string() },
+            Message::ChatSessionCreated { request_id: 29, session_id: 10 },
+            Message::UserMessageAppended { request_id: 30, session_id: 10 },
+            Message::ChatHistory { request_id: 31, session_id: 10, messages: chat() },
+            Message::ChatSessionDeleted { request_id: 32, session_id: 10 },
+            Message::Error { request_id: Some(33), code: ErrorCode::ChatSessionBusy, message: "busy".to_string() },
+            Message::Error { request_id: None, code: ErrorCode::MalformedMessage, message: String::new() },
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips_in_every_codec() {
+        for codec in CODECS {
+            for message in every_message() {
+                let data = codec.encode(&message).unwrap_or_else(|err| panic!("{} can't encode {:?}: {}", codec.name(), message, err));
+                let decoded = codec.decode(&data).unwrap_or_else(|err| panic!("{} can't decode {:?}: {}", codec.name(), message, err));
+                assert_eq!(decoded, message, "{} changed the message", codec.name());
+            }
+        }
+    }
+
+    #[test]
+    fn text_codecs_encode_utf8() {
+        for codec in CODECS {
+            for message in every_message() {
+                if codec.is_text() {
+                    assert!(String::from_utf8(codec.encode(&message).unwrap()).is_ok());
+                }
+            }
+        }
+        assert!(JsonCodec.is_text());
+        assert!(!BincodeCodec.is_text());
+        assert!(!MessagePackCodec.is_text());
+    }
+
+    #[test]
+    fn hello_codec_is_detected() {
+        for codec in CODECS {
+            let hello = Message::Hello { protocol_version: crate::PROTOCOL_VERSION, capabilities: vec!["chat".to_string()], codec: codec.name().to_string() };
+            let (detected, decoded) = decode_hello(&codec.encode(&hello).unwrap()).expect("the Hello wasn't detected");
+            assert_eq!(detected.name(), codec.name());
+            assert_eq!(decoded, hello);
+        }
+    }
+
+    #[test]
+    fn hello_naming_another_codec_is_not_detected() {
+        for codec in CODECS {
+            for name in CODECS.iter().map(|codec| codec.name()).chain(["yaml"]).filter(|name| *name != codec.name()) {
+                let hello = Message::Hello { protocol_version: crate::PROTOCOL_VERSION, capabilities: Vec::new(), codec: name.to_string() };
+                assert!(decode_hello(&codec.encode(&hello).unwrap()).is_none(), "a Hello in {} naming {} was detected", codec.name(), name);
+            }
+        }
+    }
+
+    #[test]
+    fn other_messages_are_not_taken_for_a_hello() {
+        for codec in CODECS {
+            for message in every_message().into_iter().filter(|message| !matches!(message, Message::Hello { .. })) {
+                assert!(decode_hello(&codec.encode(&message).unwrap()).is_none(), "{:?} in {} was taken for a Hello", message, codec.name());
+            }
+        }
+        assert!(decode_hello(b"").is_none());
+        assert!(decode_hello(b"{\"Hello\":{}}").is_none());
+        assert!(decode_hello(&[0xff; 16]).is_none());
+    }
+}
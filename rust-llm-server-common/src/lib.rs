@@ -1,32 +1,341 @@
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize)]
+pub mod codec;
+
+/// Version of the protocol spoken by this crate. Bumped whenever `Message` or any type it
+/// contains changes in a way that breaks clients built against an older version.
+pub const PROTOCOL_VERSION: u32 = 11;
+
+/// Optional features a client and the server agree on during the handshake.
+pub mod capabilities {
+    /// `TokenGenerated` / `LineGenerated` pushes for prompts sent with `stream` set
+    pub const STREAMING: &str = "streaming";
+    /// `CancelGeneration` requests
+    pub const CANCEL: &str = "cancel";
+    /// `GenerationQueued` / `QueuePositionChanged` / `GenerationStarted` notifications
+    pub const QUEUE: &str = "queue";
+    /// `GenerationParams` in `GeneratePrompt`, `GenerateChat` and `GenerateChatReply`
+    pub const GENERATION_PARAMS: &str = "generation_params";
+    /// `ListModels` requests and picking the model in `GeneratePrompt`, `GenerateChat` and `CreateChatSession`
+    pub const MODELS: &str = "models";
+    /// `GenerateChat` requests
+    pub const CHAT: &str = "chat";
+    /// Chat sessions kept on the server, from `CreateChatSession` to `DeleteChatSession`
+    pub const CHAT_SESSIONS: &str = "chat_sessions";
+    /// `LoadModel` / `UnloadModel` / `ReloadModel` requests. Only offered to clients the server trusts.
+    pub const ADMIN: &str = "admin";
+}
+
+/// Client-chosen identifier of a request, echoed back in every response to it.
+pub type RequestId = u64;
+
+/// Server-chosen identifier of a chat session. Sessions aren't tied to the connection that created
+/// them, anyone who knows the id can use it.
+pub type ChatSessionId = u64;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Message {
+    // handshake; these variants must stay first and their fields are only ever appended to, so
+    // that a client and server built against different versions of this crate can always tell
+    // each other their versions. A `Hello` the server can't decode is answered with `HelloRejected`.
+    /// The first message a client sends after connecting. It can be encoded with any of
+    /// `codec::CODECS`, `codec` being the name of the one it is encoded with, e.g. `json`. The
+    /// server then uses that codec for the rest of the connection.
+    Hello { protocol_version: u32, capabilities: Vec<String>, codec: String },
+    /// The server accepted the `Hello`. `capabilities` are the ones supported by both sides, and
+    /// `codec` is the name of the codec of the connection.
+    Welcome { protocol_version: u32, capabilities: Vec<String>, codec: String },
+    /// The server can't talk to the client. The connection is closed after this message.
+    HelloRejected { protocol_version: u32, reason: String },
+
     // from client to server
-    GeneratePrompt(String),
-    RequestCurrentGeneratedLines,
+    /// With `stream` set, the output is pushed to the client with `TokenGenerated` and
+    /// `LineGenerated` messages while it is being generated. Parameters left out use the server's defaults,
+    /// and `model` is the name of one of the models listed by `ListModels`, `None` meaning the default model.
+    GeneratePrompt { request_id: RequestId, prompt: String, stream: bool, params: Option<GenerationParams>, model: Option<String> },
+    /// Like `GeneratePrompt`, but the prompt is a conversation that the server renders in the format
+    /// of the model. The output is the model's reply to the last message, or the rest of the last
+    /// message if it is the assistant's.
+    GenerateChat { request_id: RequestId, messages: Vec<ChatMessage>, stream: bool, params: Option<GenerationParams>, model: Option<String> },
+    /// `request_id` is the id of the `GeneratePrompt` request whose output is wanted.
+    RequestCurrentGeneratedLines { request_id: RequestId },
+    /// Stop the `GeneratePrompt` request with the given id, whether it is running or still queued.
+    CancelGeneration { request_id: RequestId },
+    /// Ask for every model the server was configured with, answered with `ModelList`.
+    ListModels { request_id: RequestId },
+    /// Start a conversation that the server keeps the history of, answered with `ChatSessionCreated`.
+    /// `model` is fixed for the lifetime of the session, `None` meaning the default model. Sessions
+    /// that aren't used for the server's idle timeout are forgotten.
+    ///
+    /// Each model keeps the evaluated transcript of only one session. A reply to the session whose
+    /// reply was the last prompt on its model only evaluates the new messages. Any other prompt on that
+    /// model replaces it, so sessions taking turns evaluate their whole transcript again, apart from
+    /// the tokens it starts with that are the same as those of the prompt before it.
+    CreateChatSession { request_id: RequestId, model: Option<String>, system_prompt: Option<String> },
+    /// Add a user message to the history of a session, answered with `UserMessageAppended`.
+    AppendUserMessage { request_id: RequestId, session_id: ChatSessionId, content: String },
+    /// Generate the assistant's reply to the last user message of a session. This is answered like
+    /// `GeneratePrompt`, and the reply is added to the history once it's done, unless it was cancelled.
+    GenerateChatReply { request_id: RequestId, session_id: ChatSessionId, stream: bool, params: Option<GenerationParams> },
+    /// Ask for every message of a session, answered with `ChatHistory`.
+    GetChatHistory { request_id: RequestId, session_id: ChatSessionId },
+    /// Forget a session and its history, answered with `ChatSessionDeleted`.
+    DeleteChatSession { request_id: RequestId, session_id: ChatSessionId },
+    /// Load a model from a file under a new name, or the unloaded or failed model with that name again.
+    /// Settings left out are taken over from the model being replaced, or use the server's defaults.
+    /// `chat_template` is one of the names accepted in the server's config, e.g. `chatml`.
+    /// Answered with `ModelLoaded` once the model is ready.
+    LoadModel {
+        request_id: RequestId,
+        name: String,
+        path: Option<String>,
+        context_size: Option<usize>,
+        gpu_layers: Option<i32>,
+        chat_template: Option<String>,
+    },
+    /// Free a model, answered with `ModelUnloaded`. The prompt running on it is waited for, or stopped
+    /// if `cancel` is set, and its queued prompts fail. A stopped prompt is answered with an `Error` of
+    /// `ModelUnavailable` rather than `GenerationCancelled`, which only answers `CancelGeneration`.
+    UnloadModel { request_id: RequestId, name: String, cancel: bool },
+    /// Unload a model and load it again from its file, e.g. after the file was replaced.
+    /// Answered with `ModelLoaded`.
+    ReloadModel { request_id: RequestId, name: String, cancel: bool },
 
     // from server to client
-    GenerationDone(GenerationResults),
-    CurrentGeneratedLinesResponse(Vec<String>)
+    GenerationDone { request_id: RequestId, results: GenerationResults },
+    CurrentGeneratedLinesResponse { request_id: RequestId, lines: Vec<String> },
+    /// The prompt was accepted and waits in the queue at the given 1-based position.
+    GenerationQueued { request_id: RequestId, position: usize },
+    /// Jobs ahead of the prompt were started or removed, moving it up the queue.
+    QueuePositionChanged { request_id: RequestId, position: usize },
+    GenerationStarted { request_id: RequestId },
+    /// A single token generated for a streaming prompt.
+    TokenGenerated { request_id: RequestId, token: String },
+    /// A complete line of output of a streaming prompt, `line_index` being its index in
+    /// `GenerationResults::full_generated_lines`. Lines are shaped by `GenerationParams::output`.
+    LineGenerated { request_id: RequestId, line_index: usize, line: String },
+    /// Reply to `CancelGeneration`, holding whatever was generated before the prompt was stopped.
+    GenerationCancelled { request_id: RequestId, results: GenerationResults },
+    ModelList { request_id: RequestId, models: Vec<ModelInfo> },
+    ModelLoaded { request_id: RequestId, model: ModelInfo },
+    ModelUnloaded { request_id: RequestId, name: String },
+    ChatSessionCreated { request_id: RequestId, session_id: ChatSessionId },
+    UserMessageAppended { request_id: RequestId, session_id: ChatSessionId },
+    ChatHistory { request_id: RequestId, session_id: ChatSessionId, messages: Vec<ChatMessage> },
+    ChatSessionDeleted { request_id: RequestId, session_id: ChatSessionId },
+    /// A request failed. `request_id` is `None` if the failing request couldn't be identified,
+    /// e.g. because the frame couldn't be decoded.
+    Error { request_id: Option<RequestId>, code: ErrorCode, message: String },
+}
+
+impl Message {
+    /// The id of the request this message is or answers, `None` for the handshake.
+    pub fn request_id(&self) -> Option<RequestId> {
+        match self {
+            Message::Hello { .. } | Message::Welcome { .. } | Message::HelloRejected { .. } => None,
+            Message::GeneratePrompt { request_id, .. }
+            | Message::GenerateChat { request_id, .. }
+            | Message::RequestCurrentGeneratedLines { request_id }
+            | Message::CancelGeneration { request_id }
+            | Message::ListModels { request_id }
+            | Message::CreateChatSession { request_id, .. }
+            | Message::AppendUserMessage { request_id, .. }
+            | Message::GenerateChatReply { request_id, .. }
+            | Message::GetChatHistory { request_id, .. }
+            | Message::DeleteChatSession { request_id, .. }
+            | Message::LoadModel { request_id, .. }
+            | Message::UnloadModel { request_id, .. }
+            | Message::ReloadModel { request_id, .. }
+            | Message::GenerationDone { request_id, .. }
+            | Message::CurrentGeneratedLinesResponse { request_id, .. }
+            | Message::GenerationQueued { request_id, .. }
+            | Message::QueuePositionChanged { request_id, .. }
+            | Message::GenerationStarted { request_id }
+            | Message::TokenGenerated { request_id, .. }
+            | Message::LineGenerated { request_id, .. }
+            | Message::GenerationCancelled { request_id, .. }
+            | Message::ModelList { request_id, .. }
+            | Message::ModelLoaded { request_id, .. }
+            | Message::ModelUnloaded { request_id, .. }
+            | Message::ChatSessionCreated { request_id, .. }
+            | Message::UserMessageAppended { request_id, .. }
+            | Message::ChatHistory { request_id, .. }
+            | Message::ChatSessionDeleted { request_id, .. } => Some(*request_id),
+            Message::Error { request_id, .. } => *request_id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The frame couldn't be decoded into a `Message`
+    MalformedMessage,
+    /// A valid message that a client isn't supposed to send, such as a response variant
+    UnexpectedMessage,
+    /// A generation parameter was out of range
+    InvalidParams,
+    /// The job queue is full, the request may be retried later
+    QueueFull,
+    /// The request id is already used by another pending request of the same client
+    DuplicateRequestId,
+    /// There is no pending request with the given id
+    UnknownRequest,
+    /// The model file couldn't be loaded
+    ModelLoadFailed,
+    /// The prompt couldn't be converted to tokens
+    TokenizationFailed,
+    /// The prompt and generated tokens don't fit into the model's context
+    ContextOverflow,
+    /// The prompt or generated output isn't valid text
+    InvalidText,
+    /// The model failed while evaluating or sampling
+    InferenceFailed,
+    /// A request was sent before the `Hello` handshake was completed
+    HandshakeRequired,
+    /// The request relies on a capability that wasn't agreed on during the handshake
+    UnsupportedCapability,
+    /// The server has no model with the requested name
+    UnknownModel,
+    /// The model was unloaded, or is being loaded or unloaded
+    ModelUnavailable,
+    /// There is no chat session with the given id
+    UnknownChatSession,
+    /// A reply is being generated for the chat session, which has to finish or be cancelled first
+    ChatSessionBusy,
+}
+
+/// Per-request generation parameters, `None` meaning the server default.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct GenerationParams {
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub temp: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_history_length: Option<usize>,
+    pub tfs_z: Option<f32>,
+    pub typical_p: Option<f32>,
+    /// Maximum number of tokens to generate
+    pub max_tokens: Option<usize>,
+    pub seed: Option<u32>,
+    pub thread_count: Option<usize>,
+    /// Generation stops as soon as the output contains any of these strings
+    pub stop: Option<Vec<String>>,
+    /// The processors the output goes through, in order, before it is handed out as lines. `None`
+    /// splits the output into trimmed lines. Servers before protocol version 11 also stripped every
+    /// `#` by default, which `[StripChars { chars: "#" }, SplitLines]` still does.
+    pub output: Option<Vec<OutputProcessorKind>>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// A step of turning the generated text into the lines of `LineGenerated` and
+/// `GenerationResults::full_generated_lines`. Tokens are always handed out as they were generated.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputProcessorKind {
+    /// Keep the output as it is, handing it out as a single line once it is complete
+    Raw,
+    /// Split the output on newlines, trimming every line and leaving out the empty ones
+    SplitLines,
+    /// Remove every occurrence of any of `chars`
+    StripChars { chars: String },
+    /// Remove markdown syntax such as headings, list markers, emphasis and links, keeping
+    /// the contents of code blocks as they are
+    StripMarkdown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+}
+
+/// A single turn of a conversation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+/// A model the server can generate with.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ModelInfo {
+    pub name: String,
+    /// Number of tokens the prompt and the generated output have to share
+    pub context_size: usize,
+    pub status: ModelStatus,
+    /// Whether prompts that don't name a model are generated with this one
+    pub is_default: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ModelStatus {
+    /// The model is still being read, prompts for it wait in the queue until it's done
+    Loading,
+    Loaded,
+    /// The model couldn't be loaded, prompts for it are rejected
+    Failed { reason: String },
+    /// The model is freed once the prompt running on it is done, prompts for it are rejected
+    Unloading,
+    /// The model was freed by an admin, prompts for it are rejected until it is loaded again
+    Unloaded,
+}
+
+/// Why the output of a prompt ended.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model ended its output
+    EndOfStream,
+    /// `GenerationParams::max_tokens` tokens were generated
+    MaxTokens,
+    /// The output contained one of `GenerationParams::stop`
+    StopSequence,
+    /// The prompt was cancelled, or its client disconnected
+    Cancelled,
+    /// The prompt and output filled the model's context
+    ContextFull,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct GenerationResults {
-    pub was_terminated: bool,
+    pub stop_reason: StopReason,
     pub full_generated_lines: Vec<String>,
+    /// The output exactly as generated, before being split into lines
+    pub full_generated_text: String,
+    /// Time spent evaluating the prompt
     pub feed_prompt_dur_ms: u128,
+    /// Time spent generating the output after the prompt was evaluated
     pub predict_dur_ms: u128,
+    /// Time from the start of the generation until the first token was generated, `None` if there was none
+    pub time_to_first_token_ms: Option<u128>,
+    pub prompt_tokens: usize,
+    /// Number of prompt tokens the model still had evaluated from an earlier prompt, and skipped
+    pub reused_prompt_tokens: usize,
     pub predict_tokens: usize,
 }
 
 impl GenerationResults {
+    /// Results of a prompt that was terminated before anything was generated.
+    pub fn terminated_before_start() -> Self {
+        Self {
+            stop_reason: StopReason::Cancelled,
+            full_generated_lines: Vec::new(),
+            full_generated_text: String::new(),
+            feed_prompt_dur_ms: 0,
+            predict_dur_ms: 0,
+            time_to_first_token_ms: None,
+            prompt_tokens: 0,
+            reused_prompt_tokens: 0,
+            predict_tokens: 0,
+        }
+    }
+
     pub fn create_inference_stats_array(&self, total_topics_gen: i32) -> Vec<f32> {
         let mut res = Vec::new();
         res.push(total_topics_gen as f32);
         res.push(self.feed_prompt_dur_ms as f32);
         res.push(self.predict_dur_ms as f32);
-        res.push(self.predict_dur_ms as f32 / self.predict_tokens as f32);
+        res.push(if self.predict_tokens > 0 { self.predict_dur_ms as f32 / self.predict_tokens as f32 } else { 0.0 });
 
         res
     }
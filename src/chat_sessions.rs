@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use rust_llm_server_common::{ChatMessage, ChatRole, ChatSessionId, ErrorCode, RequestId};
+
+use crate::client_sessions::ClientId;
+use crate::server_error::ServerError;
+
+/// A conversation kept on the server, so that clients only send the new messages.
+pub(crate) struct ChatSession {
+    /// Name of the model the replies are generated with
+    pub(crate) model: String,
+    pub(crate) messages: Vec<ChatMessage>,
+    /// The prompt request generating the next assistant message, if there is one
+    pub(crate) pending_reply: Option<(ClientId, RequestId)>,
+    last_used: Instant,
+}
+
+impl ChatSession {
+    /// Fail if a reply is being generated, since the history is about to change.
+    pub(crate) fn check_not_busy(&self, session_id: ChatSessionId) -> Result<(), ServerError> {
+        match self.pending_reply {
+            Some((_, request_id)) => Err(ServerError::new(
+                ErrorCode::ChatSessionBusy,
+                format!("prompt request {} is generating a reply for chat session {}", request_id, session_id),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Every chat session, whichever client created it. Sessions that weren't used for `idle_timeout`
+/// are forgotten, unless a reply is being generated for them.
+pub(crate) struct ChatSessions {
+    sessions: HashMap<ChatSessionId, ChatSession>,
+    idle_timeout: Duration,
+}
+
+impl ChatSessions {
+    pub(crate) fn new(idle_timeout: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            idle_timeout,
+        }
+    }
+
+    pub(crate) fn create(&mut self, model: String, system_prompt: Option<String>) -> ChatSessionId {
+        self.remove_idle();
+
+        // ids are random, so that other clients can't guess them
+        let mut session_id = rand::random::<ChatSessionId>();
+        while self.sessions.contains_key(&session_id) {
+            session_id = rand::random();
+        }
+
+        let messages = system_prompt
+            .map(|content| ChatMessage { role: ChatRole::System, content })
+            .into_iter()
+            .collect();
+        self.sessions.insert(session_id, ChatSession { model, messages, pending_reply: None, last_used: Instant::now() });
+        session_id
+    }
+
+    pub(crate) fn get(&mut self, session_id: ChatSessionId) -> Result<&ChatSession, ServerError> {
+        self.get_mut(session_id).map(|session| &*session)
+    }
+
+    pub(crate) fn get_mut(&mut self, session_id: ChatSessionId) -> Result<&mut ChatSession, ServerError> {
+        self.remove_idle();
+        let session = self.sessions.get_mut(&session_id).ok_or_else(|| unknown_session(session_id))?;
+        session.last_used = Instant::now();
+        Ok(session)
+    }
+
+    pub(crate) fn remove(&mut self, session_id: ChatSessionId) -> Result<(), ServerError> {
+        self.get(session_id)?.check_not_busy(session_id)?;
+        self.sessions.remove(&session_id);
+        Ok(())
+    }
+
+    /// Clear the pending reply of whichever session the prompt request was generating one for,
+    /// adding `reply` to its history if there is one.
+    pub(crate) fn finish_reply(&mut self, client: ClientId, request_id: RequestId, reply: Option<String>) {
+        let Some(session) = self.sessions.values_mut().find(|session| session.pending_reply == Some((client, request_id))) else {
+            return;
+        };
+
+        session.pending_reply = None;
+        session.last_used = Instant::now();
+        if let Some(content) = reply {
+            session.messages.push(ChatMessage { role: ChatRole::Assistant, content });
+        }
+    }
+
+    /// Forget every session that wasn't used for the idle timeout and isn't waiting for a reply.
+    fn remove_idle(&mut self) {
+        let idle_timeout = self.idle_timeout;
+        self.sessions.retain(|session_id, session| {
+            let is_idle = session.pending_reply.is_none() && session.last_used.elapsed() >= idle_timeout;
+            if is_idle {
+                println!("chat session {} expired", session_id);
+            }
+            !is_idle
+        });
+    }
+}
+
+fn unknown_session(session_id: ChatSessionId) -> ServerError {
+    ServerError::new(ErrorCode::UnknownChatSession, format!("there is no chat session with id {}", session_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn make_idle(sessions: &mut ChatSessions, session_id: ChatSessionId) {
+        sessions.sessions.get_mut(&session_id).unwrap().last_used = Instant::now() - IDLE_TIMEOUT;
+    }
+
+    #[test]
+    fn idle_sessions_are_forgotten() {
+        let mut sessions = ChatSessions::new(IDLE_TIMEOUT);
+        let idle = sessions.create("model".to_string(), None);
+        let used = sessions.create("model".to_string(), Some("Be brief.".to_string()));
+        make_idle(&mut sessions, idle);
+
+        assert_eq!(sessions.get(used).unwrap().messages.len(), 1);
+        assert!(matches!(sessions.get(idle), Err(err) if err.code == ErrorCode::UnknownChatSession));
+    }
+
+    #[test]
+    fn using_a_session_keeps_it() {
+        let mut sessions = ChatSessions::new(IDLE_TIMEOUT);
+        let session_id = sessions.create("model".to_string(), None);
+        sessions.sessions.get_mut(&session_id).unwrap().last_used = Instant::now() - IDLE_TIMEOUT / 2;
+        sessions.get_mut(session_id).unwrap();
+        sessions.sessions.get_mut(&session_id).unwrap().last_used += IDLE_TIMEOUT / 4;
+
+        // only half of the timeout passed since it was last used
+        sessions.create("model".to_string(), None);
+        assert!(sessions.get(session_id).is_ok());
+    }
+
+    #[test]
+    fn sessions_waiting_for_a_reply_are_kept() {
+        let mut sessions = ChatSessions::new(IDLE_TIMEOUT);
+        let session_id = sessions.create("model".to_string(), None);
+        sessions.get_mut(session_id).unwrap().pending_reply = Some((ClientId::Unix(1), 7));
+        make_idle(&mut sessions, session_id);
+
+        assert!(sessions.get(session_id).is_ok());
+        sessions.finish_reply(ClientId::Unix(1), 7, Some("Hello.".to_string()));
+        let session = sessions.get(session_id).unwrap();
+        assert_eq!(session.pending_reply, None);
+        assert_eq!(session.messages, [ChatMessage { role: ChatRole::Assistant, content: "Hello.".to_string() }]);
+    }
+}
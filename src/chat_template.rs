@@ -0,0 +1,296 @@
+use std::str::FromStr;
+use rust_llm_server_common::{ChatMessage, ChatRole};
+use serde::Deserialize;
+
+const VICUNA_SYSTEM_PROMPT: &str = "A chat between a curious user and an artificial intelligence assistant. \
+    The assistant gives helpful, detailed, and polite answers to the user's questions.";
+const ALPACA_SYSTEM_PROMPT: &str = "Below is an instruction that describes a task. Write a response that appropriately completes the request.";
+
+/// The prompt format a model was trained on. Rendered prompts leave out the BOS and EOS tokens (`<s>`
+/// and `</s>`), which can't be written as text, so turns are only told apart by their markers. The
+/// tokenizer adds the leading BOS.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ChatTemplate {
+    /// `SYSTEM USER: ... ASSISTANT: ... USER: ...`, used by Vicuna v1.1 and its fine-tunes
+    #[default]
+    Vicuna,
+    /// `[INST] <<SYS>>...<</SYS>> ... [/INST] ... [INST] ...`
+    Llama2,
+    /// `### Instruction:` / `### Response:` sections
+    Alpaca,
+    /// `<|im_start|>role ...<|im_end|>`
+    ChatMl,
+    /// `[INST] ... [/INST]... [INST] ...`, which has no system prompt of its own
+    Mistral,
+}
+
+impl ChatTemplate {
+    pub(crate) const ALL: [ChatTemplate; 5] = [ChatTemplate::Vicuna, ChatTemplate::Llama2, ChatTemplate::Alpaca, ChatTemplate::ChatMl, ChatTemplate::Mistral];
+
+    fn name(self) -> &'static str {
+        match self {
+            ChatTemplate::Vicuna => "vicuna",
+            ChatTemplate::Llama2 => "llama2",
+            ChatTemplate::Alpaca => "alpaca",
+            ChatTemplate::ChatMl => "chatml",
+            ChatTemplate::Mistral => "mistral",
+        }
+    }
+
+    /// Render a conversation into a prompt, ending with an open assistant turn for the model to complete.
+    /// If the conversation ends with an assistant message, that message is the start of the open turn
+    /// and the model continues it.
+    pub(crate) fn render(self, messages: &[ChatMessage]) -> String {
+        let system: Vec<&str> = messages
+            .iter()
+            .filter(|message| message.role == ChatRole::System)
+            .map(|message| message.content.as_str())
+            .collect();
+        let system = (!system.is_empty()).then(|| system.join("\n"));
+        let mut turns: Vec<&ChatMessage> = messages.iter().filter(|message| message.role != ChatRole::System).collect();
+        let open_turn = match turns.last() {
+            Some(message) if message.role == ChatRole::Assistant => turns.pop().map(|message| message.content.as_str()),
+            _ => None,
+        };
+
+        let mut prompt = String::new();
+        match self {
+            ChatTemplate::Vicuna => {
+                prompt += system.as_deref().unwrap_or(VICUNA_SYSTEM_PROMPT);
+                for message in turns {
+                    let role = if message.role == ChatRole::User { "USER" } else { "ASSISTANT" };
+                    prompt += &format!(" {}: {}", role, message.content);
+                }
+                prompt += " ASSISTANT:";
+                if let Some(content) = open_turn {
+                    prompt += &format!(" {}", content);
+                }
+            },
+            ChatTemplate::Llama2 | ChatTemplate::Mistral => {
+                // the system prompt goes into the first instruction, Mistral has no markers for it
+                let mut system = system.map(|system| match self {
+                    ChatTemplate::Llama2 => format!("<<SYS>>\n{}\n<</SYS>>\n\n", system),
+                    _ => format!("{}\n\n", system),
+                });
+                let mut push_instruction = |prompt: &mut String, content: &str| {
+                    if !prompt.is_empty() {
+                        *prompt += " ";
+                    }
+                    *prompt += &format!("[INST] {}{} [/INST]", system.take().unwrap_or_default(), content);
+                };
+                let answer_separator = if self == ChatTemplate::Llama2 { " " } else { "" };
+                for message in turns {
+                    match message.role {
+                        ChatRole::User => push_instruction(&mut prompt, &message.content),
+                        _ => {
+                            // an answer needs an instruction before it, which also holds the system prompt
+                            if prompt.is_empty() {
+                                push_instruction(&mut prompt, "");
+                            }
+                            prompt += &format!("{}{}", answer_separator, message.content);
+                        },
+                    }
+                }
+                // a conversation of nothing but a system prompt is answered like an empty instruction
+                if prompt.is_empty() {
+                    push_instruction(&mut prompt, "");
+                }
+                if let Some(content) = open_turn {
+                    prompt += &format!("{}{}", answer_separator, content);
+                }
+            },
+            ChatTemplate::Alpaca => {
+                prompt += system.as_deref().unwrap_or(ALPACA_SYSTEM_PROMPT);
+                for message in turns {
+                    match message.role {
+                        ChatRole::User => prompt += &format!("\n\n### Instruction:\n{}", message.content),
+                        _ => prompt += &format!("\n\n### Response:\n{}", message.content),
+                    }
+                }
+                prompt += "\n\n### Response:\n";
+                prompt += open_turn.unwrap_or_default();
+            },
+            ChatTemplate::ChatMl => {
+                if let Some(system) = system {
+                    prompt += &format!("<|im_start|>system\n{}<|im_end|>\n", system);
+                }
+                for message in turns {
+                    let role = if message.role == ChatRole::User { "user" } else { "assistant" };
+                    prompt += &format!("<|im_start|>{}\n{}<|im_end|>\n", role, message.content);
+                }
+                prompt += "<|im_start|>assistant\n";
+                prompt += open_turn.unwrap_or_default();
+            },
+        }
+        prompt
+    }
+
+    /// Text the model produces when it's done with its turn and starts writing the next one itself.
+    /// The end of stream token isn't always generated for the markers, as they are plain text here.
+    pub(crate) fn stop_sequences(self) -> Vec<String> {
+        let stop_sequences: &[&str] = match self {
+            ChatTemplate::Vicuna => &["</s>", "USER:"],
+            ChatTemplate::Llama2 | ChatTemplate::Mistral => &["</s>", "[INST]"],
+            ChatTemplate::Alpaca => &["### Instruction:"],
+            ChatTemplate::ChatMl => &["<|im_end|>", "<|im_start|>"],
+        };
+        stop_sequences.iter().map(|stop| stop.to_string()).collect()
+    }
+}
+
+impl FromStr for ChatTemplate {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        ChatTemplate::ALL.into_iter().find(|template| template.name() == name).ok_or_else(|| {
+            let names: Vec<&str> = ChatTemplate::ALL.iter().map(|template| template.name()).collect();
+            format!("unknown chat template {}, expected one of {}", name, names.join(", "))
+        })
+    }
+}
+
+/// What a prompt is generated from.
+pub(crate) enum PromptInput {
+    /// A prompt that is fed to the model as it is
+    Text(String),
+    /// A conversation, rendered with the chat template of the model
+    Chat(Vec<ChatMessage>),
+}
+
+impl PromptInput {
+    /// The prompt to feed the model, along with the stop sequences ending the model's turn.
+    pub(crate) fn render(self, template: ChatTemplate) -> (String, Vec<String>) {
+        match self {
+            PromptInput::Text(prompt) => (prompt, Vec::new()),
+            PromptInput::Chat(messages) => (template.render(&messages), template.stop_sequences()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: ChatRole, content: &str) -> ChatMessage {
+        ChatMessage { role, content: content.to_string() }
+    }
+
+    fn single_turn() -> Vec<ChatMessage> {
+        vec![message(ChatRole::System, "Be brief."), message(ChatRole::User, "Hi!")]
+    }
+
+    fn multi_turn() -> Vec<ChatMessage> {
+        vec![
+            message(ChatRole::System, "Be brief."),
+            message(ChatRole::User, "Hi!"),
+            message(ChatRole::Assistant, "Hello."),
+            message(ChatRole::User, "How are you?"),
+        ]
+    }
+
+    fn system_only() -> Vec<ChatMessage> {
+        vec![message(ChatRole::System, "Be brief.")]
+    }
+
+    #[test]
+    fn vicuna() {
+        let template = ChatTemplate::Vicuna;
+        assert_eq!(template.render(&single_turn()), "Be brief. USER: Hi! ASSISTANT:");
+        assert_eq!(template.render(&multi_turn()), "Be brief. USER: Hi! ASSISTANT: Hello. USER: How are you? ASSISTANT:");
+        assert_eq!(template.render(&system_only()), "Be brief. ASSISTANT:");
+        assert_eq!(template.render(&single_turn()[1..]), format!("{} USER: Hi! ASSISTANT:", VICUNA_SYSTEM_PROMPT));
+    }
+
+    #[test]
+    fn llama2() {
+        let template = ChatTemplate::Llama2;
+        assert_eq!(template.render(&single_turn()), "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi! [/INST]");
+        assert_eq!(template.render(&multi_turn()), "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi! [/INST] Hello. [INST] How are you? [/INST]");
+        assert_eq!(template.render(&system_only()), "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\n [/INST]");
+        assert_eq!(template.render(&single_turn()[1..]), "[INST] Hi! [/INST]");
+    }
+
+    #[test]
+    fn alpaca() {
+        let template = ChatTemplate::Alpaca;
+        assert_eq!(template.render(&single_turn()), "Be brief.\n\n### Instruction:\nHi!\n\n### Response:\n");
+        assert_eq!(
+            template.render(&multi_turn()),
+            "Be brief.\n\n### Instruction:\nHi!\n\n### Response:\nHello.\n\n### Instruction:\nHow are you?\n\n### Response:\n"
+        );
+        assert_eq!(template.render(&system_only()), "Be brief.\n\n### Response:\n");
+        assert_eq!(template.render(&single_turn()[1..]), format!("{}\n\n### Instruction:\nHi!\n\n### Response:\n", ALPACA_SYSTEM_PROMPT));
+    }
+
+    #[test]
+    fn chatml() {
+        let template = ChatTemplate::ChatMl;
+        assert_eq!(
+            template.render(&single_turn()),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            template.render(&multi_turn()),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\nHello.<|im_end|>\n\
+             <|im_start|>user\nHow are you?<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(template.render(&system_only()), "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>assistant\n");
+        assert_eq!(template.render(&single_turn()[1..]), "<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\n");
+    }
+
+    #[test]
+    fn mistral() {
+        let template = ChatTemplate::Mistral;
+        assert_eq!(template.render(&single_turn()), "[INST] Be brief.\n\nHi! [/INST]");
+        assert_eq!(template.render(&multi_turn()), "[INST] Be brief.\n\nHi! [/INST]Hello. [INST] How are you? [/INST]");
+        assert_eq!(template.render(&system_only()), "[INST] Be brief.\n\n [/INST]");
+        assert_eq!(template.render(&single_turn()[1..]), "[INST] Hi! [/INST]");
+    }
+
+    #[test]
+    fn last_assistant_message_is_continued() {
+        let mut messages = single_turn();
+        messages.push(message(ChatRole::Assistant, "Hel"));
+        for template in ChatTemplate::ALL {
+            let rendered = template.render(&messages);
+            let open_turn = template.render(&single_turn());
+            assert!(rendered.starts_with(&open_turn), "{:?} doesn't open the assistant turn: {:?}", template, rendered);
+            assert_eq!(rendered[open_turn.len()..].trim_start(), "Hel", "{:?}", template);
+        }
+    }
+
+    #[test]
+    fn next_turn_starts_with_the_reply() {
+        // the context of a chat session is only reused if the next prompt starts with the prompt and reply before it
+        let mut with_reply = single_turn();
+        with_reply.push(message(ChatRole::Assistant, "Hello."));
+        let mut next_turn = with_reply.clone();
+        next_turn.push(message(ChatRole::User, "How are you?"));
+        for template in ChatTemplate::ALL {
+            assert!(template.render(&with_reply).starts_with(&template.render(&single_turn())), "{:?}", template);
+            assert!(template.render(&next_turn).starts_with(&template.render(&with_reply)), "{:?}", template);
+        }
+    }
+
+    #[test]
+    fn conversation_starting_with_the_assistant_keeps_the_system_prompt() {
+        let messages = vec![message(ChatRole::System, "Be brief."), message(ChatRole::Assistant, "Hello."), message(ChatRole::User, "Hi!")];
+        assert_eq!(ChatTemplate::Llama2.render(&messages), "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\n [/INST] Hello. [INST] Hi! [/INST]");
+        assert_eq!(ChatTemplate::Mistral.render(&messages), "[INST] Be brief.\n\n [/INST]Hello. [INST] Hi! [/INST]");
+    }
+
+    #[test]
+    fn system_messages_are_joined() {
+        let messages = vec![message(ChatRole::System, "Be brief."), message(ChatRole::User, "Hi!"), message(ChatRole::System, "Be kind.")];
+        assert_eq!(ChatTemplate::Vicuna.render(&messages), "Be brief.\nBe kind. USER: Hi! ASSISTANT:");
+    }
+
+    #[test]
+    fn templates_are_found_by_name() {
+        for template in ChatTemplate::ALL {
+            assert_eq!(template.name().parse::<ChatTemplate>(), Ok(template));
+        }
+        assert!("gpt".parse::<ChatTemplate>().is_err());
+    }
+}
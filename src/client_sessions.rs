@@ -0,0 +1,87 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use message_io::network::Endpoint;
+use rust_llm_server_common::codec::Codec;
+use rust_llm_server_common::RequestId;
+
+use crate::codec::default_codec;
+
+/// Identifies whoever submitted a job, so that its output can be routed back to them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum ClientId {
+    /// A client connected through message-io, i.e. over framed TCP
+    Net(Endpoint),
+    /// A client connected through the Unix domain socket
+    Unix(u64),
+    /// A client connected through the WebSocket listener
+    WebSocket(u64),
+    /// A single HTTP request
+    Http(u64),
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientId::Net(endpoint) => write!(f, "{}", endpoint),
+            ClientId::Unix(id) => write!(f, "unix client {}", id),
+            ClientId::WebSocket(id) => write!(f, "websocket client {}", id),
+            ClientId::Http(id) => write!(f, "http request {}", id),
+        }
+    }
+}
+
+/// Per-connection state of a single client.
+pub(crate) struct ClientSession {
+    /// Ids of the prompts submitted by this client that haven't finished generating yet
+    pub(crate) pending_requests: HashSet<RequestId>,
+    /// Capabilities agreed on during the handshake, `None` until the client sent its `Hello`
+    pub(crate) capabilities: Option<Vec<String>>,
+    /// Encoding of the messages sent to and from this client, detected from its `Hello`
+    pub(crate) codec: &'static dyn Codec,
+}
+
+impl ClientSession {
+    pub(crate) fn new(client: ClientId) -> Self {
+        Self {
+            pending_requests: HashSet::new(),
+            capabilities: None,
+            codec: default_codec(client),
+        }
+    }
+
+    pub(crate) fn is_handshake_done(&self) -> bool {
+        self.capabilities.is_some()
+    }
+
+    pub(crate) fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.as_ref().is_some_and(|capabilities| capabilities.iter().any(|c| c == capability))
+    }
+}
+
+/// Registry of all currently connected clients.
+#[derive(Default)]
+pub(crate) struct ClientSessions {
+    sessions: HashMap<ClientId, ClientSession>,
+}
+
+impl ClientSessions {
+    pub(crate) fn add(&mut self, client: ClientId) {
+        self.sessions.insert(client, ClientSession::new(client));
+    }
+
+    pub(crate) fn remove(&mut self, client: &ClientId) -> Option<ClientSession> {
+        self.sessions.remove(client)
+    }
+
+    pub(crate) fn get(&self, client: &ClientId) -> Option<&ClientSession> {
+        self.sessions.get(client)
+    }
+
+    pub(crate) fn get_mut(&mut self, client: &ClientId) -> Option<&mut ClientSession> {
+        self.sessions.get_mut(client)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.sessions.len()
+    }
+}
@@ -0,0 +1,12 @@
+use rust_llm_server_common::codec::{BincodeCodec, Codec, JsonCodec};
+
+use crate::client_sessions::ClientId;
+
+/// The codec a client is answered with until it sent a `Hello`, depending on the transport it connected with.
+pub(crate) fn default_codec(client: ClientId) -> &'static dyn Codec {
+    match client {
+        // browser based tools can't decode bincode
+        ClientId::WebSocket(_) => &JsonCodec,
+        _ => &BincodeCodec,
+    }
+}
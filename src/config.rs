@@ -0,0 +1,518 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+use crate::chat_template::ChatTemplate;
+use crate::generation_params::{check_positive, check_range, check_unit_interval, MAX_REPEAT_PENALTY, MAX_TEMP};
+
+/// Config file used when neither `--config` nor `LLM_SERVER_CONFIG` name one, it's fine for it not to exist
+const DEFAULT_CONFIG_PATH: &str = "llm-server.toml";
+
+/// Every setting that can be overridden, by its key in the config file along with its environment variable.
+/// The command line flag is the key with dots and underscores turned into dashes, e.g. `--model-context-size`.
+const SETTINGS: [(&str, &str); 21] = [
+    ("model.path", "LLM_SERVER_MODEL_PATH"),
+    ("model.context_size", "LLM_SERVER_CONTEXT_SIZE"),
+    ("model.gpu_layers", "LLM_SERVER_GPU_LAYERS"),
+    ("model.chat_template", "LLM_SERVER_CHAT_TEMPLATE"),
+    ("generation.thread_count", "LLM_SERVER_THREAD_COUNT"),
+    ("generation.max_tokens", "LLM_SERVER_MAX_TOKENS"),
+    ("generation.top_k", "LLM_SERVER_TOP_K"),
+    ("generation.top_p", "LLM_SERVER_TOP_P"),
+    ("generation.temp", "LLM_SERVER_TEMP"),
+    ("generation.repeat_penalty", "LLM_SERVER_REPEAT_PENALTY"),
+    ("generation.repeat_history_length", "LLM_SERVER_REPEAT_HISTORY_LENGTH"),
+    ("generation.tfs_z", "LLM_SERVER_TFS_Z"),
+    ("generation.typical_p", "LLM_SERVER_TYPICAL_P"),
+    ("listeners.tcp", "LLM_SERVER_ADDR"),
+    ("listeners.websocket", "LLM_SERVER_WS_ADDR"),
+    ("listeners.http", "LLM_SERVER_HTTP_ADDR"),
+    ("listeners.unix_socket", "LLM_SERVER_UNIX_SOCKET"),
+    ("listeners.unix_socket_mode", "LLM_SERVER_UNIX_SOCKET_MODE"),
+    ("queue.capacity", "LLM_SERVER_QUEUE_CAPACITY"),
+    ("chat_sessions.idle_timeout_secs", "LLM_SERVER_CHAT_SESSION_IDLE_TIMEOUT"),
+    ("admin.allow_network", "LLM_SERVER_ADMIN_ALLOW_NETWORK"),
+];
+
+#[derive(Debug)]
+pub(crate) struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Everything the server can be configured with. Values come from, in increasing priority, the
+/// defaults below, the TOML config file, `LLM_SERVER_*` environment variables (including `.env`)
+/// and command line flags.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    /// The model prompts are generated with unless they name another one
+    pub(crate) model: ModelConfig,
+    /// Further models by the name clients pick them with, these can only be set in the config file
+    pub(crate) models: BTreeMap<String, ModelConfig>,
+    pub(crate) generation: GenerationConfig,
+    pub(crate) listeners: ListenerConfig,
+    pub(crate) queue: QueueConfig,
+    pub(crate) chat_sessions: ChatSessionConfig,
+    pub(crate) admin: AdminConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ModelConfig {
+    /// GGUF file of the model
+    pub(crate) path: PathBuf,
+    /// Number of tokens the prompt and the generated output have to share
+    pub(crate) context_size: usize,
+    /// Number of layers to offload to the GPU
+    pub(crate) gpu_layers: i32,
+    /// Prompt format conversations are rendered in for this model
+    pub(crate) chat_template: ChatTemplate,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("models/wizard-vicuna-uncensored-7b/Wizard-Vicuna-7B-Uncensored.Q3_K_M.gguf"),
+            context_size: 1024,
+            gpu_layers: 0,
+            chat_template: ChatTemplate::default(),
+        }
+    }
+}
+
+impl ModelConfig {
+    /// Name the default model is known by to clients
+    pub(crate) fn name(&self) -> String {
+        self.path.file_stem().unwrap_or_default().to_string_lossy().into_owned()
+    }
+
+    pub(crate) fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        if !self.path.is_file() {
+            return Err(ConfigError(format!("{}.path: {} is not a file", key, self.path.display())));
+        }
+        check_range(&format!("{}.context_size", key), self.context_size, 1..=i32::MAX as usize).map_err(ConfigError)?;
+        check_range(&format!("{}.gpu_layers", key), self.gpu_layers, 0..=i32::MAX).map_err(ConfigError)?;
+        Ok(())
+    }
+}
+
+/// Defaults for everything a client leaves out of `GenerationParams`.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct GenerationConfig {
+    pub(crate) thread_count: usize,
+    pub(crate) max_tokens: usize,
+    pub(crate) top_k: i32,
+    pub(crate) top_p: f32,
+    pub(crate) temp: f32,
+    pub(crate) repeat_penalty: f32,
+    pub(crate) repeat_history_length: usize,
+    pub(crate) tfs_z: f32,
+    pub(crate) typical_p: f32,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            thread_count: 8.min(max_thread_count()),
+            max_tokens: 1024,
+            top_k: 40,
+            top_p: 0.75,
+            temp: 0.25,
+            repeat_penalty: 1.1,
+            repeat_history_length: 64,
+            tfs_z: 1.0,
+            typical_p: 1.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ListenerConfig {
+    /// Address of the framed TCP listener
+    pub(crate) tcp: Option<String>,
+    /// Address of the WebSocket listener, which speaks JSON by default
+    pub(crate) websocket: Option<String>,
+    /// Address of the OpenAI-compatible HTTP API
+    pub(crate) http: Option<String>,
+    /// Path of the Unix domain socket
+    pub(crate) unix_socket: Option<PathBuf>,
+    /// Permission bits of the Unix domain socket, by default only the user running the server may connect
+    pub(crate) unix_socket_mode: u32,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            tcp: None,
+            websocket: None,
+            http: None,
+            unix_socket: None,
+            unix_socket_mode: 0o600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct QueueConfig {
+    /// Number of prompts that may wait for the model before new ones are rejected
+    pub(crate) capacity: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self { capacity: 16 }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ChatSessionConfig {
+    /// Seconds after which a chat session that wasn't used is forgotten
+    pub(crate) idle_timeout_secs: u64,
+}
+
+impl Default for ChatSessionConfig {
+    fn default() -> Self {
+        Self { idle_timeout_secs: 60 * 60 }
+    }
+}
+
+/// Who may load and unload models.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AdminConfig {
+    /// Offer the admin capability to network clients as well, not only to those on the Unix domain socket
+    pub(crate) allow_network: bool,
+}
+
+pub(crate) fn max_thread_count() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+impl ServerConfig {
+    /// Every model by name, the default one first.
+    pub(crate) fn model_configs(&self) -> Vec<(String, &ModelConfig)> {
+        let mut models = vec![(self.model.name(), &self.model)];
+        models.extend(self.models.iter().map(|(name, model)| (name.clone(), model)));
+        models
+    }
+
+    /// Build the configuration from every layer and validate it.
+    pub(crate) fn load() -> Result<Self, ConfigError> {
+        Self::load_from(std::env::args().skip(1), |env_var| dotenvy::var(env_var).ok())
+    }
+
+    /// Build the configuration from the given command line arguments, looking up environment variables with `env_var`.
+    fn load_from(args: impl Iterator<Item = String>, env_var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let args = parse_args(args)?;
+
+        let config_path = args
+            .iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, path)| PathBuf::from(path))
+            .or_else(|| env_var("LLM_SERVER_CONFIG").map(PathBuf::from));
+        let mut config = match &config_path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Self::default(),
+        };
+
+        for (key, var) in SETTINGS {
+            if let Some(value) = env_var(var) {
+                config.set(key, &value).map_err(|err| ConfigError(format!("{}: {}", var, err)))?;
+            }
+        }
+        for (key, value) in args.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value).map_err(|err| ConfigError(format!("--{}: {}", key.replace(['.', '_'], "-"), err)))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|err| ConfigError(format!("unable to read {}: {}", path.display(), err)))?;
+        toml::from_str(&contents).map_err(|err| ConfigError(format!("invalid config file {}: {}", path.display(), err)))
+    }
+
+    /// Override a single setting by its key.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "model.path" => self.model.path = PathBuf::from(value),
+            "model.context_size" => self.model.context_size = parse(value)?,
+            "model.gpu_layers" => self.model.gpu_layers = parse(value)?,
+            "model.chat_template" => self.model.chat_template = value.parse()?,
+            "generation.thread_count" => self.generation.thread_count = parse(value)?,
+            "generation.max_tokens" => self.generation.max_tokens = parse(value)?,
+            "generation.top_k" => self.generation.top_k = parse(value)?,
+            "generation.top_p" => self.generation.top_p = parse(value)?,
+            "generation.temp" => self.generation.temp = parse(value)?,
+            "generation.repeat_penalty" => self.generation.repeat_penalty = parse(value)?,
+            "generation.repeat_history_length" => self.generation.repeat_history_length = parse(value)?,
+            "generation.tfs_z" => self.generation.tfs_z = parse(value)?,
+            "generation.typical_p" => self.generation.typical_p = parse(value)?,
+            "listeners.tcp" => self.listeners.tcp = Some(value.to_string()),
+            "listeners.websocket" => self.listeners.websocket = Some(value.to_string()),
+            "listeners.http" => self.listeners.http = Some(value.to_string()),
+            "listeners.unix_socket" => self.listeners.unix_socket = Some(PathBuf::from(value)),
+            "listeners.unix_socket_mode" => {
+                let mode = value.trim_start_matches("0o");
+                self.listeners.unix_socket_mode = u32::from_str_radix(mode, 8).map_err(|_| format!("{} is not an octal file mode", value))?;
+            },
+            "queue.capacity" => self.queue.capacity = parse(value)?,
+            "chat_sessions.idle_timeout_secs" => self.chat_sessions.idle_timeout_secs = parse(value)?,
+            "admin.allow_network" => self.admin.allow_network = parse(value)?,
+            _ => return Err("unknown setting".to_string()),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.model.validate("model")?;
+        let default_name = self.model.name();
+        for (name, model) in &self.models {
+            let key = format!("models.{}", name);
+            if name.is_empty() || *name == default_name {
+                return Err(ConfigError(format!("{}: the name is empty or already used by the default model", key)));
+            }
+            model.validate(&key)?;
+            // a missing path falls back to the default model's file, which would load it twice
+            if let Some((other_name, _)) = self.model_configs().into_iter().find(|(other_name, other)| other_name != name && other.path == model.path) {
+                return Err(ConfigError(format!("{}.path: {} is already used by model {}", key, model.path.display(), other_name)));
+            }
+        }
+        // the defaults have to fit the largest model, they are capped for the smaller ones
+        let max_context_size = self.model_configs().iter().map(|(_, model)| model.context_size).max().unwrap_or_default();
+
+        let generation = &self.generation;
+        check_range("generation.thread_count", generation.thread_count, 1..=max_thread_count()).map_err(ConfigError)?;
+        check_range("generation.max_tokens", generation.max_tokens, 1..=max_context_size).map_err(ConfigError)?;
+        check_range("generation.top_k", generation.top_k, 1..=i32::MAX).map_err(ConfigError)?;
+        check_unit_interval("generation.top_p", generation.top_p).map_err(ConfigError)?;
+        check_positive("generation.temp", generation.temp, MAX_TEMP).map_err(ConfigError)?;
+        check_positive("generation.repeat_penalty", generation.repeat_penalty, MAX_REPEAT_PENALTY).map_err(ConfigError)?;
+        check_range("generation.repeat_history_length", generation.repeat_history_length, 0..=max_context_size).map_err(ConfigError)?;
+        check_unit_interval("generation.tfs_z", generation.tfs_z).map_err(ConfigError)?;
+        check_unit_interval("generation.typical_p", generation.typical_p).map_err(ConfigError)?;
+
+        let listeners = &self.listeners;
+        if listeners.tcp.is_none() && listeners.websocket.is_none() && listeners.http.is_none() && listeners.unix_socket.is_none() {
+            return Err(ConfigError(
+                "no listeners configured, set at least one of listeners.tcp, listeners.websocket, listeners.http or listeners.unix_socket".to_string(),
+            ));
+        }
+        check_range("listeners.unix_socket_mode", listeners.unix_socket_mode, 0..=0o777).map_err(ConfigError)?;
+        check_range("queue.capacity", self.queue.capacity, 1..=usize::MAX).map_err(ConfigError)?;
+        check_range("chat_sessions.idle_timeout_secs", self.chat_sessions.idle_timeout_secs, 1..=u64::MAX).map_err(ConfigError)?;
+        Ok(())
+    }
+}
+
+/// Split the command line into `(key, value)` pairs, accepting both `--flag value` and `--flag=value`.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut parsed = Vec::new();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError(format!("unexpected argument {}", arg)));
+        };
+        if flag == "help" {
+            print_usage();
+            std::process::exit(0);
+        }
+
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), value.to_string()),
+            None => {
+                let value = args.next().ok_or_else(|| ConfigError(format!("--{} needs a value", flag)))?;
+                (flag.to_string(), value)
+            },
+        };
+        let key = match flag.as_str() {
+            "config" => "config",
+            _ => SETTINGS
+                .iter()
+                .map(|(key, _)| *key)
+                .find(|key| key.replace(['.', '_'], "-") == flag)
+                .ok_or_else(|| ConfigError(format!("unknown flag --{}, see --help", flag)))?,
+        };
+        parsed.push((key.to_string(), value));
+    }
+    Ok(parsed)
+}
+
+fn print_usage() {
+    println!("usage: rust-llm-server [--config <file>] [--<setting> <value>]...");
+    println!();
+    println!("settings, along with the environment variables overriding the config file:");
+    for (key, env_var) in SETTINGS {
+        println!("  --{:<36} {}", key.replace(['.', '_'], "-"), env_var);
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+
+    /// A file in the temp directory that is removed again when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("llm-server-config-test-{}-{}", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<ServerConfig, ConfigError> {
+        let env: HashMap<String, String> = env.iter().map(|(var, value)| (var.to_string(), value.to_string())).collect();
+        ServerConfig::load_from(args.iter().map(|arg| arg.to_string()), |var| env.get(var).cloned())
+    }
+
+    /// A config that passes validation, using `model` as the model file.
+    fn valid_config(model: &TempFile) -> ServerConfig {
+        let mut config = ServerConfig::default();
+        config.model.path = model.0.clone();
+        config.listeners.tcp = Some("127.0.0.1:5341".to_string());
+        config
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let model = TempFile::new("layers.gguf", "");
+        let file = TempFile::new(
+            "layers.toml",
+            &format!(
+                "[model]\npath = {:?}\ncontext_size = 512\n[generation]\nmax_tokens = 100\ntop_k = 10\ntemp = 0.5\n[listeners]\ntcp = \"127.0.0.1:1\"\n",
+                model.0.display().to_string()
+            ),
+        );
+        let config_path = file.0.display().to_string();
+        let env = [("LLM_SERVER_CONFIG", config_path.as_str()), ("LLM_SERVER_MAX_TOKENS", "200"), ("LLM_SERVER_TOP_K", "20")];
+
+        let config = load(&["--generation-top-k", "30"], &env).unwrap();
+        // the file overrides the defaults
+        assert_eq!(config.model.context_size, 512);
+        assert_eq!(config.generation.temp, 0.5);
+        assert_eq!(config.listeners.tcp.as_deref(), Some("127.0.0.1:1"));
+        // the environment overrides the file
+        assert_eq!(config.generation.max_tokens, 200);
+        // the command line overrides the environment
+        assert_eq!(config.generation.top_k, 30);
+        // untouched settings keep their defaults
+        assert_eq!(config.generation.top_p, GenerationConfig::default().top_p);
+    }
+
+    #[test]
+    fn config_flag_overrides_config_variable() {
+        let model = TempFile::new("flag.gguf", "");
+        let file = TempFile::new("flag.toml", &format!("[model]\npath = {:?}\n[listeners]\nhttp = \"127.0.0.1:2\"\n", model.0.display().to_string()));
+        let config_path = file.0.display().to_string();
+
+        let config = load(&["--config", &config_path], &[("LLM_SERVER_CONFIG", "/nonexistent/llm-server.toml")]).unwrap();
+        assert_eq!(config.listeners.http.as_deref(), Some("127.0.0.1:2"));
+        let config = load(&[&format!("--config={}", config_path)], &[]).unwrap();
+        assert_eq!(config.listeners.http.as_deref(), Some("127.0.0.1:2"));
+        assert!(load(&[], &[("LLM_SERVER_CONFIG", "/nonexistent/llm-server.toml")]).is_err());
+    }
+
+    #[test]
+    fn unix_socket_mode_is_octal() {
+        let mut config = ServerConfig::default();
+        config.set("listeners.unix_socket_mode", "640").unwrap();
+        assert_eq!(config.listeners.unix_socket_mode, 0o640);
+        config.set("listeners.unix_socket_mode", "0o660").unwrap();
+        assert_eq!(config.listeners.unix_socket_mode, 0o660);
+        config.set("listeners.unix_socket_mode", "0600").unwrap();
+        assert_eq!(config.listeners.unix_socket_mode, 0o600);
+        assert!(config.set("listeners.unix_socket_mode", "680").is_err());
+        assert!(config.set("listeners.unix_socket_mode", "rw-------").is_err());
+
+        let config: ServerConfig = toml::from_str("[listeners]\nunix_socket_mode = 0o640\n").unwrap();
+        assert_eq!(config.listeners.unix_socket_mode, 0o640);
+    }
+
+    #[test]
+    fn unknown_flags_and_settings_are_rejected() {
+        assert!(load(&["--no-such-setting", "1"], &[]).is_err());
+        assert!(load(&["--generation-top-k"], &[]).is_err());
+        assert!(load(&["positional"], &[]).is_err());
+        assert!(ServerConfig::default().set("generation.top_k", "many").is_err());
+        assert!(toml::from_str::<ServerConfig>("[generation]\nno_such_setting = 1\n").is_err());
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let model = TempFile::new("example.gguf", "");
+        let mut config: ServerConfig = toml::from_str(&fs::read_to_string("llm-server.example.toml").unwrap()).unwrap();
+        config.model.path = model.0.clone();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn every_invalid_setting_is_rejected() {
+        let model = TempFile::new("invalid.gguf", "");
+        let other_model = TempFile::new("invalid-other.gguf", "");
+        valid_config(&model).validate().unwrap();
+
+        type BreakConfig<'a> = Box<dyn Fn(&mut ServerConfig) + 'a>;
+        let cases: Vec<(&str, BreakConfig)> = vec![
+            ("model.path", Box::new(|config| config.model.path = PathBuf::from("/nonexistent/model.gguf"))),
+            ("model.context_size", Box::new(|config| config.model.context_size = 0)),
+            ("model.gpu_layers", Box::new(|config| config.model.gpu_layers = -1)),
+            ("models.: the name is empty", Box::new(|config| {
+                config.models.insert(String::new(), ModelConfig { path: other_model.0.clone(), ..ModelConfig::default() });
+            })),
+            ("already used by the default model", Box::new(|config| {
+                config.models.insert(config.model.name(), ModelConfig { path: other_model.0.clone(), ..ModelConfig::default() });
+            })),
+            ("models.other.path", Box::new(|config| {
+                config.models.insert("other".to_string(), ModelConfig { path: config.model.path.clone(), ..ModelConfig::default() });
+            })),
+            ("models.other.context_size", Box::new(|config| {
+                config.models.insert("other".to_string(), ModelConfig { path: other_model.0.clone(), context_size: 0, ..ModelConfig::default() });
+            })),
+            ("generation.thread_count", Box::new(|config| config.generation.thread_count = 0)),
+            ("generation.thread_count", Box::new(|config| config.generation.thread_count = max_thread_count() + 1)),
+            ("generation.max_tokens", Box::new(|config| config.generation.max_tokens = 0)),
+            ("generation.max_tokens", Box::new(|config| config.generation.max_tokens = config.model.context_size + 1)),
+            ("generation.top_k", Box::new(|config| config.generation.top_k = 0)),
+            ("generation.top_p", Box::new(|config| config.generation.top_p = 1.5)),
+            ("generation.temp", Box::new(|config| config.generation.temp = 0.0)),
+            ("generation.repeat_penalty", Box::new(|config| config.generation.repeat_penalty = -1.0)),
+            ("generation.repeat_history_length", Box::new(|config| config.generation.repeat_history_length = config.model.context_size + 1)),
+            ("generation.tfs_z", Box::new(|config| config.generation.tfs_z = 0.0)),
+            ("generation.typical_p", Box::new(|config| config.generation.typical_p = f32::NAN)),
+            ("no listeners configured", Box::new(|config| config.listeners.tcp = None)),
+            ("listeners.unix_socket_mode", Box::new(|config| config.listeners.unix_socket_mode = 0o1777)),
+            ("queue.capacity", Box::new(|config| config.queue.capacity = 0)),
+            ("chat_sessions.idle_timeout_secs", Box::new(|config| config.chat_sessions.idle_timeout_secs = 0)),
+        ];
+        for (expected, break_config) in cases {
+            let mut config = valid_config(&model);
+            break_config(&mut config);
+            let err = config.validate().expect_err(expected).to_string();
+            assert!(err.contains(expected), "expected an error about {}, got: {}", expected, err);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::ops::RangeInclusive;
+use llama_cpp_rs::{LGeneratorParams, LSampleParams};
+use rust_llm_server_common::{GenerationParams, OutputProcessorKind};
+
+use crate::config::{max_thread_count, GenerationConfig};
+use crate::output_processor::default_output;
+
+pub(crate) const MAX_TEMP: f32 = 2.0;
+pub(crate) const MAX_REPEAT_PENALTY: f32 = 2.0;
+
+/// Generation parameters with the defaults filled in and every value checked.
+pub(crate) struct ResolvedParams {
+    pub(crate) generator_params: LGeneratorParams,
+    pub(crate) seed: u32,
+    pub(crate) output: Vec<OutputProcessorKind>,
+}
+
+/// Fill in the configured defaults for everything the client didn't specify, and make sure the values
+/// it did specify are in range for the model with the given context size. The error is meant to be shown to the client.
+pub(crate) fn resolve_params(params: Option<GenerationParams>, defaults: &GenerationConfig, context_size: usize) -> Result<ResolvedParams, String> {
+    let params = params.unwrap_or_default();
+
+    let sample_params = LSampleParams {
+        top_k: check_range("top_k", params.top_k.unwrap_or(defaults.top_k), 1..=i32::MAX)?,
+        top_p: check_unit_interval("top_p", params.top_p.unwrap_or(defaults.top_p))?,
+        temp: check_positive("temp", params.temp.unwrap_or(defaults.temp), MAX_TEMP)?,
+        repeat_penalty: check_positive("repeat_penalty", params.repeat_penalty.unwrap_or(defaults.repeat_penalty), MAX_REPEAT_PENALTY)?,
+        repeat_history_length: check_range(
+            "repeat_history_length",
+            params.repeat_history_length.unwrap_or(defaults.repeat_history_length.min(context_size)),
+            0..=context_size,
+        )?,
+        tfs_z: check_unit_interval("tfs_z", params.tfs_z.unwrap_or(defaults.tfs_z))?,
+        typical_p: check_unit_interval("typical_p", params.typical_p.unwrap_or(defaults.typical_p))?,
+    };
+
+    let stop = params.stop.unwrap_or_default();
+    if stop.iter().any(|stop| stop.is_empty()) {
+        return Err("stop strings must not be empty".to_string());
+    }
+
+    let output = params.output.unwrap_or_else(default_output);
+    if output.is_empty() {
+        return Err("output must name at least one processor".to_string());
+    }
+
+    Ok(ResolvedParams {
+        generator_params: LGeneratorParams {
+            generate_tokens: check_range("max_tokens", params.max_tokens.unwrap_or(defaults.max_tokens.min(context_size)), 1..=context_size)?,
+            worker_thread_count: check_range(
+                "thread_count",
+                params.thread_count.unwrap_or(defaults.thread_count),
+                1..=max_thread_count(),
+            )?,
+            sample_params,
+            stop_sequences: stop,
+        },
+        seed: params.seed.unwrap_or_else(rand::random::<u32>),
+        output,
+    })
+}
+
+pub(crate) fn check_range<T: PartialOrd + std::fmt::Display>(name: &str, value: T, range: RangeInclusive<T>) -> Result<T, String> {
+    if !range.contains(&value) {
+        return Err(format!("{} must be between {} and {}, got {}", name, range.start(), range.end(), value));
+    }
+    Ok(value)
+}
+
+/// Check a value is in (0, 1].
+pub(crate) fn check_unit_interval(name: &str, value: f32) -> Result<f32, String> {
+    if !(value > 0.0 && value <= 1.0) {
+        return Err(format!("{} must be greater than 0 and at most 1, got {}", name, value));
+    }
+    Ok(value)
+}
+
+/// Check a value is in (0, max].
+pub(crate) fn check_positive(name: &str, value: f32, max: f32) -> Result<f32, String> {
+    if !(value > 0.0 && value <= max) {
+        return Err(format!("{} must be greater than 0 and at most {}, got {}", name, max, value));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(params: GenerationParams, context_size: usize) -> Result<ResolvedParams, String> {
+        resolve_params(Some(params), &GenerationConfig::default(), context_size)
+    }
+
+    /// The error resolving `params` for a context of 512 tokens gives.
+    fn error(params: GenerationParams) -> String {
+        match resolve(params, 512) {
+            Ok(_) => panic!("expected the params to be rejected"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn defaults_fill_in_what_the_client_left_out() {
+        let defaults = GenerationConfig::default();
+        let resolved = resolve_params(None, &defaults, 2048).unwrap();
+        let generator_params = &resolved.generator_params;
+        assert_eq!(generator_params.generate_tokens, defaults.max_tokens);
+        assert_eq!(generator_params.worker_thread_count, defaults.thread_count);
+        assert_eq!(generator_params.sample_params.top_k, defaults.top_k);
+        assert_eq!(generator_params.sample_params.temp, defaults.temp);
+        assert_eq!(generator_params.sample_params.repeat_history_length, defaults.repeat_history_length);
+        assert!(generator_params.stop_sequences.is_empty());
+        assert_eq!(resolved.output, default_output());
+    }
+
+    #[test]
+    fn client_values_override_the_defaults() {
+        let params = GenerationParams {
+            top_k: Some(5),
+            top_p: Some(0.5),
+            temp: Some(1.5),
+            repeat_penalty: Some(1.3),
+            repeat_history_length: Some(16),
+            tfs_z: Some(0.9),
+            typical_p: Some(0.8),
+            max_tokens: Some(1),
+            seed: Some(42),
+            thread_count: Some(1),
+            stop: Some(vec!["\n\n".to_string()]),
+            output: Some(vec![OutputProcessorKind::Raw]),
+        };
+        let resolved = resolve(params, 512).unwrap();
+        let generator_params = &resolved.generator_params;
+        let sample_params = &generator_params.sample_params;
+        assert_eq!(
+            (sample_params.top_k, sample_params.top_p, sample_params.temp, sample_params.repeat_penalty),
+            (5, 0.5, 1.5, 1.3)
+        );
+        assert_eq!((sample_params.repeat_history_length, sample_params.tfs_z, sample_params.typical_p), (16, 0.9, 0.8));
+        assert_eq!((generator_params.generate_tokens, generator_params.worker_thread_count), (1, 1));
+        assert_eq!(generator_params.stop_sequences, ["\n\n"]);
+        assert_eq!(resolved.seed, 42);
+        assert_eq!(resolved.output, [OutputProcessorKind::Raw]);
+    }
+
+    #[test]
+    fn defaults_are_capped_at_the_context_size() {
+        let defaults = GenerationConfig { max_tokens: 4096, repeat_history_length: 4096, ..Default::default() };
+        let resolved = resolve_params(None, &defaults, 256).unwrap();
+        assert_eq!(resolved.generator_params.generate_tokens, 256);
+        assert_eq!(resolved.generator_params.sample_params.repeat_history_length, 256);
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        assert_eq!(error(GenerationParams { max_tokens: Some(0), ..Default::default() }), "max_tokens must be between 1 and 512, got 0");
+        assert_eq!(error(GenerationParams { max_tokens: Some(513), ..Default::default() }), "max_tokens must be between 1 and 512, got 513");
+        assert_eq!(
+            error(GenerationParams { repeat_history_length: Some(513), ..Default::default() }),
+            "repeat_history_length must be between 0 and 512, got 513"
+        );
+        assert!(error(GenerationParams { thread_count: Some(0), ..Default::default() }).starts_with("thread_count must be between 1 and"));
+        assert!(error(GenerationParams { thread_count: Some(max_thread_count() + 1), ..Default::default() }).starts_with("thread_count must be between 1 and"));
+        assert!(error(GenerationParams { top_k: Some(0), ..Default::default() }).starts_with("top_k"));
+        assert!(error(GenerationParams { top_p: Some(0.0), ..Default::default() }).starts_with("top_p"));
+        assert!(error(GenerationParams { typical_p: Some(1.5), ..Default::default() }).starts_with("typical_p"));
+        assert!(error(GenerationParams { temp: Some(MAX_TEMP + 0.1), ..Default::default() }).starts_with("temp"));
+        assert!(error(GenerationParams { repeat_penalty: Some(0.0), ..Default::default() }).starts_with("repeat_penalty"));
+        assert!(error(GenerationParams { temp: Some(f32::NAN), ..Default::default() }).starts_with("temp"));
+    }
+
+    #[test]
+    fn empty_stop_strings_and_outputs_are_rejected() {
+        assert_eq!(
+            error(GenerationParams { stop: Some(vec!["end".to_string(), String::new()]), ..Default::default() }),
+            "stop strings must not be empty"
+        );
+        assert_eq!(error(GenerationParams { output: Some(Vec::new()), ..Default::default() }), "output must name at least one processor");
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rust_llm_server_common::{ChatMessage, ChatRole, ErrorCode, GenerationParams, GenerationResults, ModelStatus, StopReason};
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::chat_template::PromptInput;
+use crate::client_sessions::ClientId;
+use crate::generation_params::resolve_params;
+use crate::job_queue::{Job, JobQueue};
+use crate::config::ServerConfig;
+use crate::llm_runner_diff_backend::{GenerationEvent, GenerationState, TerminationCause};
+use crate::model_registry::{ModelRegistry, SelectedModel};
+use crate::openai_api::{
+    ChatCompletionChoice, ChatCompletionChunk, ChatCompletionChunkChoice, ChatCompletionRequest, ChatCompletionResponse, ChatDelta, CompletionChoice,
+    CompletionRequest, CompletionResponse, ErrorBody, ErrorResponse, Model, ModelList, StopSequences, Usage,
+};
+use crate::server_error::ServerError;
+use crate::{exit_with_error, LlmServerMessage};
+
+/// HTTP requests waiting for the llm runner. The llm comm loop forwards everything the runner
+/// reports about a request to the thread handling it.
+#[derive(Default)]
+pub(crate) struct PendingHttpRequests {
+    senders: Mutex<HashMap<u64, Sender<LlmServerMessage>>>,
+    next_id: AtomicU64,
+}
+
+impl PendingHttpRequests {
+    fn register(&self) -> (u64, Receiver<LlmServerMessage>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = channel();
+        self.senders.lock().unwrap().insert(id, tx);
+        (id, rx)
+    }
+
+    fn unregister(&self, id: u64) {
+        self.senders.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn forward(&self, id: u64, message: LlmServerMessage) {
+        let mut senders = self.senders.lock().unwrap();
+        let is_done = matches!(message, LlmServerMessage::PromptDone(..));
+        if let Some(sender) = senders.get(&id) {
+            // the handling thread may have given up on the request already
+            let _ = sender.send(message);
+        }
+        if is_done {
+            senders.remove(&id);
+        }
+    }
+}
+
+/// An error response in the format OpenAI clients expect.
+struct HttpError {
+    status: u16,
+    error_type: &'static str,
+    code: Option<String>,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, error_type: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error_type,
+            code: None,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(400, "invalid_request_error", message)
+    }
+
+    fn into_response_body(self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorBody {
+                message: self.message,
+                error_type: self.error_type,
+                code: self.code,
+            },
+        }
+    }
+}
+
+impl From<ServerError> for HttpError {
+    fn from(error: ServerError) -> Self {
+        let (status, error_type) = match error.code {
+            ErrorCode::InvalidParams | ErrorCode::TokenizationFailed | ErrorCode::ContextOverflow | ErrorCode::InvalidText => {
+                (400, "invalid_request_error")
+            },
+            ErrorCode::UnknownModel => (404, "invalid_request_error"),
+            ErrorCode::QueueFull => (503, "server_overloaded"),
+            ErrorCode::ModelUnavailable => (503, "server_error"),
+            _ => (500, "server_error"),
+        };
+        Self {
+            status,
+            error_type,
+            code: Some(format!("{:?}", error.code)),
+            message: error.message,
+        }
+    }
+}
+
+/// Everything the request handling threads share.
+struct HttpContext {
+    config: Arc<ServerConfig>,
+    models: Arc<ModelRegistry>,
+    gen_state: Arc<Mutex<GenerationState>>,
+    job_queue: Arc<JobQueue>,
+    pending_requests: Arc<PendingHttpRequests>,
+}
+
+/// A job that was queued for an HTTP request, along with the channel its progress is reported on.
+struct SubmittedJob {
+    id: u64,
+    rx: Receiver<LlmServerMessage>,
+}
+
+pub(crate) fn run_http_server(
+    listen_addr: &str,
+    config: Arc<ServerConfig>,
+    models: Arc<ModelRegistry>,
+    gen_state: Arc<Mutex<GenerationState>>,
+    job_queue: Arc<JobQueue>,
+    pending_requests: Arc<PendingHttpRequests>,
+) {
+    let server = Server::http(listen_addr).unwrap_or_else(|err| exit_with_error(format!("unable to listen on {}: {}", listen_addr, err)));
+    println!("Llm http server running at {}", listen_addr);
+
+    let context = Arc::new(HttpContext {
+        config,
+        models,
+        gen_state,
+        job_queue,
+        pending_requests,
+    });
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let context = context.clone();
+            // completions block until the runner is done, so every request gets its own thread
+            thread::spawn(move || handle_request(request, &context));
+        }
+    });
+}
+
+fn handle_request(mut request: Request, context: &HttpContext) {
+    let path = request.url().split('?').next().unwrap_or_default().to_string();
+    let response = match (request.method(), path.as_str()) {
+        (Method::Get, "/v1/models") => Ok(json_response(200, &list_models(context))),
+        (Method::Post, "/v1/completions") => create_completion(&mut request, context).map(|completion| json_response(200, &completion)),
+        (Method::Post, "/v1/chat/completions") => match create_chat_completion(&mut request, context) {
+            Ok(ChatCompletion::Full(completion)) => Ok(json_response(200, &completion)),
+            Ok(ChatCompletion::Stream(model, job)) => {
+                stream_chat_completion(request, context, model, job);
+                return;
+            },
+            Err(err) => Err(err),
+        },
+        (method, path) => Err(HttpError::new(404, "invalid_request_error", format!("no route for {} {}", method, path))),
+    };
+
+    let response = response.unwrap_or_else(|err| {
+        println!("http request {} {} failed: {}", request.method(), path, err.message);
+        json_response(err.status, &err.into_response_body())
+    });
+    if let Err(err) = request.respond(response) {
+        println!("unable to send http response: {}", err);
+    }
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> Response<Cursor<Vec<u8>>> {
+    let data = serde_json::to_vec(body).unwrap();
+    Response::from_data(data)
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default()
+}
+
+/// Every model that can be generated with, models that failed to load are left out.
+fn list_models(context: &HttpContext) -> ModelList {
+    ModelList {
+        object: "list",
+        data: context
+            .models
+            .list()
+            .into_iter()
+            .filter(|model| !matches!(model.status, ModelStatus::Failed { .. }))
+            .map(|model| Model {
+                id: model.name,
+                object: "model",
+                created: 0,
+                owned_by: "rust-llm-server",
+            })
+            .collect(),
+    }
+}
+
+/// Map the sampling options shared by both completion endpoints onto `GenerationParams`.
+fn generation_params(max_tokens: Option<usize>, temperature: Option<f32>, top_p: Option<f32>, stop: Option<StopSequences>, seed: Option<u32>) -> GenerationParams {
+    let mut params = GenerationParams {
+        max_tokens,
+        top_p,
+        seed,
+        stop: stop.map(StopSequences::into_vec),
+        ..GenerationParams::default()
+    };
+    // OpenAI uses a temperature of 0 for greedy sampling, which is the same as only keeping the most likely token
+    match temperature {
+        Some(0.0) => params.top_k = Some(1),
+        temperature => params.temp = temperature,
+    }
+    params
+}
+
+/// OpenAI's name for why the output ended.
+fn finish_reason(stop_reason: StopReason) -> &'static str {
+    match stop_reason {
+        StopReason::MaxTokens | StopReason::ContextFull => "length",
+        StopReason::EndOfStream | StopReason::StopSequence | StopReason::Cancelled => "stop",
+    }
+}
+
+fn create_completion(request: &mut Request, context: &HttpContext) -> Result<CompletionResponse, HttpError> {
+    let body: CompletionRequest =
+        serde_json::from_reader(request.as_reader()).map_err(|err| HttpError::invalid_request(format!("invalid request body: {}", err)))?;
+    let model = context.models.select(body.model.as_deref())?;
+    if body.stream {
+        return Err(HttpError::invalid_request("streaming is not supported for completions"));
+    }
+
+    let params = generation_params(body.max_tokens, body.temperature, body.top_p, body.stop, body.seed);
+    let job = submit_job(PromptInput::Text(body.prompt), &model, params, false, context)?;
+    let id = job.id;
+    let results = wait_for_results(job)?;
+    Ok(CompletionResponse {
+        id: format!("cmpl-{}", id),
+        object: "text_completion",
+        created: unix_time(),
+        model: model.name,
+        choices: vec![CompletionChoice {
+            text: results.full_generated_text,
+            index: 0,
+            logprobs: None,
+            finish_reason: finish_reason(results.stop_reason),
+        }],
+        usage: Usage::new(results.prompt_tokens, results.predict_tokens),
+    })
+}
+
+enum ChatCompletion {
+    Full(ChatCompletionResponse),
+    /// The job was queued, its output is to be streamed to the client as it is generated
+    Stream(String, SubmittedJob),
+}
+
+fn create_chat_completion(request: &mut Request, context: &HttpContext) -> Result<ChatCompletion, HttpError> {
+    let body: ChatCompletionRequest =
+        serde_json::from_reader(request.as_reader()).map_err(|err| HttpError::invalid_request(format!("invalid request body: {}", err)))?;
+    let model = context.models.select(body.model.as_deref())?;
+    if body.messages.is_empty() {
+        return Err(HttpError::invalid_request("messages must not be empty"));
+    }
+
+    let params = generation_params(body.max_tokens, body.temperature, body.top_p, body.stop, body.seed);
+    let job = submit_job(PromptInput::Chat(body.messages), &model, params, body.stream, context)?;
+    if body.stream {
+        return Ok(ChatCompletion::Stream(model.name, job));
+    }
+
+    let id = job.id;
+    let results = wait_for_results(job)?;
+    Ok(ChatCompletion::Full(ChatCompletionResponse {
+        id: format!("chatcmpl-{}", id),
+        object: "chat.completion",
+        created: unix_time(),
+        model: model.name,
+        choices: vec![ChatCompletionChoice {
+            index: 0,
+            message: ChatMessage {
+                role: ChatRole::Assistant,
+                content: results.full_generated_text.trim_start().to_string(),
+            },
+            finish_reason: finish_reason(results.stop_reason),
+        }],
+        usage: Usage::new(results.prompt_tokens, results.predict_tokens),
+    }))
+}
+
+/// Send the output of a chat completion job to the client as Server-Sent Events while it is being generated.
+fn stream_chat_completion(request: Request, context: &HttpContext, model: String, job: SubmittedJob) {
+    let id = format!("chatcmpl-{}", job.id);
+    let created = unix_time();
+    let chunk = |delta: ChatDelta, finish_reason: Option<&'static str>| ChatCompletionChunk {
+        id: id.clone(),
+        object: "chat.completion.chunk",
+        created,
+        model: model.clone(),
+        choices: vec![ChatCompletionChunkChoice { index: 0, delta, finish_reason }],
+    };
+
+    // a client that went away is noticed when writing the next event to it fails
+    let result = (|| -> io::Result<()> {
+        let mut events = SseWriter::start(request.into_writer())?;
+        let role = ChatDelta { role: Some(ChatRole::Assistant), ..ChatDelta::default() };
+        events.send_event(&chunk(role, None))?;
+
+        // the reply starts at its first visible character, like the content of a full response
+        let mut is_content_started = false;
+        loop {
+            match job.rx.recv() {
+                Ok(LlmServerMessage::OutputGenerated(_, _, GenerationEvent::Token(token))) => {
+                    let token = if is_content_started { token } else { token.trim_start().to_string() };
+                    if token.is_empty() {
+                        continue;
+                    }
+                    is_content_started = true;
+                    let content = ChatDelta { content: Some(token), ..ChatDelta::default() };
+                    events.send_event(&chunk(content, None))?;
+                },
+                Ok(LlmServerMessage::PromptDone(_, _, Ok(results))) => {
+                    events.send_event(&chunk(ChatDelta::default(), Some(finish_reason(results.stop_reason))))?;
+                    break;
+                },
+                Ok(LlmServerMessage::PromptDone(_, _, Err(err))) => {
+                    events.send_event(&HttpError::from(err).into_response_body())?;
+                    break;
+                },
+                Ok(_) => {},
+                Err(_) => {
+                    events.send_event(&HttpError::new(500, "server_error", "the llm runner stopped").into_response_body())?;
+                    break;
+                },
+            }
+        }
+        events.finish()
+    })();
+
+    if let Err(err) = result {
+        println!("http request {} disconnected while streaming: {}", job.id, err);
+        cancel_job(job.id, context);
+    }
+}
+
+/// Writes Server-Sent Events to a raw connection. The response is chunk-encoded by hand, since the
+/// encoder of `tiny_http` buffers the body and would hold the events back.
+struct SseWriter {
+    writer: Box<dyn Write + Send>,
+}
+
+impl SseWriter {
+    fn start(mut writer: Box<dyn Write + Send>) -> io::Result<Self> {
+        writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nTransfer-Encoding: chunked\r\n\r\n")?;
+        writer.flush()?;
+        Ok(Self { writer })
+    }
+
+    fn send_event<T: Serialize>(&mut self, data: &T) -> io::Result<()> {
+        self.send_data(&serde_json::to_string(data).unwrap())
+    }
+
+    fn send_data(&mut self, data: &str) -> io::Result<()> {
+        let event = format!("data: {}\n\n", data);
+        write!(self.writer, "{:x}\r\n{}\r\n", event.len(), event)?;
+        self.writer.flush()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.send_data("[DONE]")?;
+        self.writer.write_all(b"0\r\n\r\n")?;
+        self.writer.flush()
+    }
+}
+
+/// Queue a prompt for the llm runner.
+fn submit_job(input: PromptInput, model: &SelectedModel, params: GenerationParams, stream: bool, context: &HttpContext) -> Result<SubmittedJob, HttpError> {
+    let mut params =
+        resolve_params(Some(params), &context.config.generation, model.context_size).map_err(|reason| ServerError::new(ErrorCode::InvalidParams, reason))?;
+    let (prompt, stop_sequences) = input.render(model.chat_template);
+    params.generator_params.stop_sequences.extend(stop_sequences);
+
+    let (id, rx) = context.pending_requests.register();
+    let job = Job {
+        client: ClientId::Http(id),
+        request_id: id,
+        prompt,
+        model: model.name.clone(),
+        chat_session: None,
+        stream,
+        params,
+    };
+    if context.job_queue.push(job).is_none() {
+        context.pending_requests.unregister(id);
+        let reason = format!("the job queue is full ({} prompts waiting), try again later", context.job_queue.capacity());
+        return Err(ServerError::new(ErrorCode::QueueFull, reason).into());
+    }
+
+    Ok(SubmittedJob { id, rx })
+}
+
+/// Block until the llm runner is done with a job. A client that disconnects in the meantime isn't
+/// noticed, since `tiny_http` doesn't expose its connection, so the job runs to completion.
+fn wait_for_results(job: SubmittedJob) -> Result<GenerationResults, HttpError> {
+    loop {
+        match job.rx.recv() {
+            Ok(LlmServerMessage::PromptDone(_, _, results)) => return Ok(results?),
+            Ok(_) => {},
+            Err(_) => return Err(HttpError::new(500, "server_error", "the llm runner stopped")),
+        }
+    }
+}
+
+/// Stop a job whose client went away, whether it is running or still queued.
+fn cancel_job(id: u64, context: &HttpContext) {
+    let client = ClientId::Http(id);
+    if context.job_queue.remove(client, id).is_some() {
+        context.pending_requests.unregister(id);
+        return;
+    }
+
+    let mut gen_state_lock = context.gen_state.lock().unwrap();
+    if gen_state_lock.is_generating && gen_state_lock.current_request == Some((client, id)) {
+        gen_state_lock.termination = Some(TerminationCause::Cancelled);
+    }
+}
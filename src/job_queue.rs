@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use rust_llm_server_common::{ChatSessionId, RequestId};
+
+use crate::client_sessions::ClientId;
+use crate::generation_params::ResolvedParams;
+
+/// A prompt waiting to be picked up by the llm runner.
+pub(crate) struct Job {
+    pub(crate) client: ClientId,
+    pub(crate) request_id: RequestId,
+    pub(crate) prompt: String,
+    /// Name of the model to generate with
+    pub(crate) model: String,
+    /// The chat session the prompt is the transcript of
+    pub(crate) chat_session: Option<ChatSessionId>,
+    /// Whether the output should be pushed to the client while it is being generated
+    pub(crate) stream: bool,
+    pub(crate) params: ResolvedParams,
+}
+
+/// Bounded FIFO queue of jobs shared between the server and the llm runner thread.
+pub(crate) struct JobQueue {
+    jobs: Mutex<VecDeque<Job>>,
+    job_available: Condvar,
+    capacity: usize,
+}
+
+impl JobQueue {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            jobs: Mutex::new(VecDeque::new()),
+            job_available: Condvar::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Add a job to the back of the queue, returning its 1-based position,
+    /// or `None` if the queue is full.
+    pub(crate) fn push(&self, job: Job) -> Option<usize> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= self.capacity {
+            return None;
+        }
+
+        jobs.push_back(job);
+        self.job_available.notify_one();
+        Some(jobs.len())
+    }
+
+    /// Take the job at the front of the queue, blocking until there is one.
+    pub(crate) fn pop(&self) -> Job {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            if let Some(job) = jobs.pop_front() {
+                return job;
+            }
+            jobs = self.job_available.wait(jobs).unwrap();
+        }
+    }
+
+    /// Remove a single queued job, e.g. when it is cancelled.
+    pub(crate) fn remove(&self, client: ClientId, request_id: RequestId) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let index = jobs.iter().position(|job| job.client == client && job.request_id == request_id)?;
+        jobs.remove(index)
+    }
+
+    /// Remove every queued job of a client, e.g. when it disconnects.
+    pub(crate) fn remove_client(&self, client: ClientId) -> Vec<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let (removed, kept): (VecDeque<Job>, VecDeque<Job>) = jobs.drain(..).partition(|job| job.client == client);
+        *jobs = kept;
+        removed.into()
+    }
+
+    /// The client, request id and 1-based position of every queued job.
+    pub(crate) fn positions(&self) -> Vec<(ClientId, RequestId, usize)> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter()
+            .enumerate()
+            .map(|(i, job)| (job.client, job.request_id, i + 1))
+            .collect()
+    }
+}
@@ -1,97 +1,168 @@
 use std::io::Write;
 use std::sync::{Arc, Mutex};
-use llama_cpp_rs::{LContext, LContextConfig, LGenerator, LGeneratorParams, LSampleParams};
-use rust_llm_server_common::GenerationResults;
+use llama_cpp_rs::{LContext, LContextConfig, LError, LGenerator, LStopReason};
+use rust_llm_server_common::{ChatSessionId, GenerationResults, RequestId, StopReason};
+
+use crate::client_sessions::ClientId;
+use crate::config::ModelConfig;
+use crate::generation_params::ResolvedParams;
+use crate::output_processor::OutputPipeline;
+
+/// Why the running prompt is stopped before it is done.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum TerminationCause {
+    /// Its client cancelled it or went away
+    Cancelled,
+    /// Its model is being unloaded
+    ModelUnloaded,
+}
 
 #[derive(Default)]
 pub(crate) struct GenerationState {
-    pub(crate) should_terminate: bool,
+    /// Set to stop the running prompt, it is taken again once the runner has stopped
+    pub(crate) termination: Option<TerminationCause>,
     pub(crate) is_generating: bool,
+    /// The client and request id of the prompt that is currently being generated
+    pub(crate) current_request: Option<(ClientId, RequestId)>,
+    /// Name of the model the current prompt is generated with
+    pub(crate) current_model: Option<String>,
     pub(crate) generated_lines: Vec<String>,
 }
 
+/// Output produced while a prompt is being generated.
+pub(crate) enum GenerationEvent {
+    /// A newly generated token, as it came out of the model
+    Token(String),
+    /// A line of output that is complete, along with its index in `generated_lines`
+    Line(usize, String),
+}
+
 pub(crate) struct LlmRunner {
+    /// Keeps the model loaded between prompts, its context is reset at the start of every run
+    /// unless the run continues the conversation the context already holds
+    generator: LGenerator,
+    /// The chat session whose transcript is in the context, along with the exact text of that transcript.
+    /// There is only this one slot, so replying to another session evaluates its whole prompt again,
+    /// apart from the tokens it starts with that are the same as those of the prompt before it.
+    context_session: Option<(ChatSessionId, String)>,
 }
 
 impl LlmRunner {
-    pub(crate) fn new() -> Self {
-        Self {
-        }
+    /// Load the model. This reads the whole model file, so it's only done once at startup.
+    pub(crate) fn new(model_config: &ModelConfig) -> Result<Self, LError> {
+        let mut config = LContextConfig::new(&model_config.path);
+        config.n_ctx = model_config.context_size as i32;
+        config.n_gpu_layers = model_config.gpu_layers;
+
+        let context = LContext::new(config)?;
+        Ok(Self {
+            generator: LGenerator::new(context),
+            context_session: None,
+        })
     }
 
-    pub(crate) fn run(&self, prompt: String, gen_state: Arc<Mutex<GenerationState>>) -> GenerationResults {
-        let mut config = LContextConfig::new("models/wizard-vicuna-uncensored-7b/Wizard-Vicuna-7B-Uncensored.Q3_K_M.gguf");
-        config.n_ctx = 1024;
-        config.seed = rand::random::<u32>();
-
-        let context = LContext::new(config).unwrap();
-        let mut generator = LGenerator::new(context);
-
-        let mut current_line = String::new();
-        generator
-            .generate_incremental(
-                &prompt,
-                LGeneratorParams {
-                    worker_thread_count: 8,
-                    sample_params: LSampleParams {
-                        top_k: 40,
-                        top_p: 0.75,
-                        repeat_penalty: 1.1,
-                        temp: 0.25,
-                        repeat_history_length: 64,
-                        ..LSampleParams::default()
-                    },
-                    generate_tokens: 1024,
-                },
-                |generated| {
-                    let t = generated[generated.len() - 1].as_str();
-                    let mut gen_state_lock = gen_state.lock().unwrap();
-                    if gen_state_lock.should_terminate {
-                        return false;
-                    }
-                    print!("{t}");
-                    std::io::stdout().flush().unwrap();
-
-                    if t.contains(&"\n".to_string()) && !current_line.is_empty() {
-                        // trim the line and push it to the array
-                        gen_state_lock.generated_lines.push(current_line.trim().to_string());
-                        current_line = String::new();
-                    } else {
-                        // remove all newlines, hashtags (the ai sometimes adds them for some reason),
-                        // and add the new token to the current line
-                        current_line += &t.replace("#", "").replace("\n", "");
-                    }
-
-                    true
-                },
-            )
-            .unwrap();
-
-        // add the rest of the generated stuff as a new line and end the execution
+    pub(crate) fn run(
+        &mut self,
+        prompt: String,
+        params: ResolvedParams,
+        chat_session: Option<ChatSessionId>,
+        gen_state: Arc<Mutex<GenerationState>>,
+        mut on_event: impl FnMut(GenerationEvent),
+    ) -> Result<GenerationResults, LError> {
+        let generator = &mut self.generator;
+        generator.context_mut().set_seed(params.seed);
+
+        // the next turn of the conversation that was generated last only needs its new messages evaluated,
+        // as long as the rendered history is exactly what the model generated. Otherwise the prompt is
+        // loaded from scratch, which still keeps the tokens it shares with what the context holds
+        let context_session = self.context_session.take();
+        let continuation = match (&context_session, chat_session) {
+            (Some((context_session, transcript)), Some(chat_session)) if *context_session == chat_session && generator.can_continue() => {
+                prompt.strip_prefix(transcript.as_str())
+            },
+            _ => None,
+        };
+
+        let mut output = OutputPipeline::new(&params.output);
+        let callback = |generated: &[String]| {
+            let t = generated[generated.len() - 1].as_str();
+            let mut gen_state_lock = gen_state.lock().unwrap();
+            if gen_state_lock.termination.is_some() {
+                return false;
+            }
+            print!("{t}");
+            std::io::stdout().flush().unwrap();
+            on_event(GenerationEvent::Token(t.to_string()));
+
+            push_lines(output.process(t), &mut gen_state_lock, &mut on_event);
+            true
+        };
+        let generation = match continuation {
+            Some(new_messages) => {
+                println!("continuing chat session {} from the context", chat_session.unwrap_or_default());
+                generator.generate_continuation(new_messages, params.generator_params, callback)?
+            },
+            None => generator.generate_incremental(&prompt, params.generator_params, callback)?,
+        };
+        if let Some(chat_session) = chat_session.filter(|_| generator.can_continue()) {
+            self.context_session = Some((chat_session, prompt + &generation.context_text));
+        }
+
+        // the callback only halts for termination requests
+        let stop_reason = match generation.stop_reason {
+            LStopReason::EndOfStream => StopReason::EndOfStream,
+            LStopReason::TokenLimit => StopReason::MaxTokens,
+            LStopReason::ContextFull => StopReason::ContextFull,
+            LStopReason::StopSequence => StopReason::StopSequence,
+            LStopReason::Halted => StopReason::Cancelled,
+        };
+
+        let stats = generator.stats();
+        let feed_prompt_dur_ms = stats.prompt_eval_duration.as_millis();
+        let predict_dur_ms = stats.generation_duration.as_millis();
+        let time_to_first_token_ms = stats.time_to_first_token.map(|duration| duration.as_millis());
+        let (prompt_tokens, reused_prompt_tokens, predict_tokens) = (stats.prompt_tokens, stats.reused_prompt_tokens, stats.generated_tokens);
+        println!("prompt of {} tokens evaluated, {} of them reused from the previous prompt", prompt_tokens, reused_prompt_tokens);
+
+        // add the rest of the generated stuff as new lines and end the execution
         let mut gen_state_lock = gen_state.lock().unwrap();
-        if gen_state_lock.should_terminate {
-            gen_state_lock.should_terminate = false;
+        if stop_reason == StopReason::Cancelled {
             gen_state_lock.is_generating = false;
             println!("terminated text gen");
 
-            GenerationResults {
-                was_terminated: true,
+            Ok(GenerationResults {
+                stop_reason,
                 full_generated_lines: gen_state_lock.generated_lines.clone(),
-                feed_prompt_dur_ms: 0,
-                predict_dur_ms: 0,
-                predict_tokens: 0,
-            }
+                full_generated_text: generation.text,
+                feed_prompt_dur_ms,
+                predict_dur_ms,
+                time_to_first_token_ms,
+                prompt_tokens,
+                reused_prompt_tokens,
+                predict_tokens,
+            })
         } else {
-            gen_state_lock.generated_lines.push(current_line.trim().to_string());
+            push_lines(output.finish(), &mut gen_state_lock, &mut on_event);
 
-            GenerationResults {
-                was_terminated: false,
+            Ok(GenerationResults {
+                stop_reason,
                 full_generated_lines: gen_state_lock.generated_lines.clone(),
-                feed_prompt_dur_ms: 0,
-                predict_dur_ms: 0,
-                predict_tokens: 0,
-            }
+                full_generated_text: generation.text,
+                feed_prompt_dur_ms,
+                predict_dur_ms,
+                time_to_first_token_ms,
+                prompt_tokens,
+                reused_prompt_tokens,
+                predict_tokens,
+            })
         }
     }
 }
 
+/// Hand out lines of output that are complete.
+fn push_lines(lines: Vec<String>, gen_state: &mut GenerationState, on_event: &mut impl FnMut(GenerationEvent)) {
+    for line in lines {
+        on_event(GenerationEvent::Line(gen_state.generated_lines.len(), line.clone()));
+        gen_state.generated_lines.push(line);
+    }
+}
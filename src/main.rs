@@ -1,156 +1,752 @@
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
-use message_io::network::{Endpoint, NetEvent, Transport};
-use message_io::node;
-use rust_llm_server_common::{Message, GenerationResults};
+use std::time::Duration;
+use message_io::network::{NetEvent, Transport};
+use message_io::node::{self, NodeHandler};
+use rust_llm_server_common::codec::{decode_hello, CodecError};
+use rust_llm_server_common::{
+    capabilities, ChatMessage, ChatRole, ChatSessionId, ErrorCode, Message, GenerationParams, GenerationResults, ModelInfo, RequestId, StopReason,
+    PROTOCOL_VERSION,
+};
 
-use crate::llm_runner_diff_backend::{GenerationState, LlmRunner};
+use crate::chat_sessions::ChatSessions;
+use crate::chat_template::{ChatTemplate, PromptInput};
+use crate::client_sessions::{ClientId, ClientSessions};
+use crate::codec::default_codec;
+use crate::config::ServerConfig;
+use crate::generation_params::resolve_params;
+use crate::http_server::{run_http_server, PendingHttpRequests};
+use crate::job_queue::{Job, JobQueue};
+use crate::llm_runner_diff_backend::{GenerationEvent, GenerationState, TerminationCause};
+use crate::model_registry::ModelRegistry;
+use crate::server_error::ServerError;
+use crate::unix_socket::{listen_unix_socket, UnixSocketEvent, UnixStreams};
+use crate::websocket::{listen_websocket, WebSocketEvent, WebSockets};
 
+mod chat_sessions;
+mod chat_template;
+mod client_sessions;
+mod codec;
+mod config;
+mod generation_params;
+mod http_server;
+mod job_queue;
 mod llm_runner;
 mod llm_runner_diff_backend;
+mod model_registry;
+mod openai_api;
+mod output_processor;
+mod server_error;
+mod unix_socket;
+mod websocket;
+
+/// Everything this server can do, offered to clients during the handshake
+const SERVER_CAPABILITIES: [&str; 8] = [
+    capabilities::STREAMING,
+    capabilities::CANCEL,
+    capabilities::QUEUE,
+    capabilities::GENERATION_PARAMS,
+    capabilities::MODELS,
+    capabilities::CHAT,
+    capabilities::CHAT_SESSIONS,
+    capabilities::ADMIN,
+];
 
 enum LlmServerMessage {
-    // from server to llm runner
-    GeneratePrompt(String),
     // from llm runner to server
-    PromptDone(GenerationResults),
+    PromptStarted(ClientId, RequestId),
+    OutputGenerated(ClientId, RequestId, GenerationEvent),
+    PromptDone(ClientId, RequestId, Result<GenerationResults, ServerError>),
+}
+
+impl LlmServerMessage {
+    fn client(&self) -> ClientId {
+        match self {
+            LlmServerMessage::PromptStarted(client, _)
+            | LlmServerMessage::OutputGenerated(client, _, _)
+            | LlmServerMessage::PromptDone(client, _, _) => *client,
+        }
+    }
 }
 
 fn main() {
-    // load env vars
-    dotenvy::dotenv().unwrap();
+    // load env vars, the config file and the command line can provide everything as well
+    dotenvy::dotenv().ok();
+    let config = Arc::new(ServerConfig::load().unwrap_or_else(|err| exit_with_error(format!("invalid configuration: {}", err))));
 
     let gen_state = Arc::new(Mutex::new(GenerationState::default()));
+    let job_queue = Arc::new(JobQueue::new(config.queue.capacity));
+    let models = Arc::new(ModelRegistry::new(&config));
 
-    let (llm_tx, llm_rx) = channel::<LlmServerMessage>();
     let (serv_tx, serv_rx) = channel::<LlmServerMessage>();
 
-    run_server(Arc::clone(&gen_state), serv_rx, llm_tx);
-    run_llm_model(Arc::clone(&gen_state), llm_rx, serv_tx);
+    let pending_http_requests = Arc::new(PendingHttpRequests::default());
+    if let Some(http_listen_addr) = &config.listeners.http {
+        run_http_server(
+            http_listen_addr,
+            Arc::clone(&config),
+            Arc::clone(&models),
+            Arc::clone(&gen_state),
+            Arc::clone(&job_queue),
+            Arc::clone(&pending_http_requests),
+        );
+    }
+
+    run_server(Arc::clone(&config), Arc::clone(&models), Arc::clone(&gen_state), Arc::clone(&job_queue), Arc::clone(&pending_http_requests), serv_rx);
+    run_llm_model(Arc::clone(&models), Arc::clone(&gen_state), Arc::clone(&job_queue), serv_tx);
 
     loop {}
 }
 
-fn run_llm_model(gen_state: Arc<Mutex<GenerationState>>, rx: Receiver<LlmServerMessage>, tx: Sender<LlmServerMessage>) {
-    let runner = LlmRunner::new();
+/// Report a problem that keeps the server from starting and quit.
+fn exit_with_error(message: String) -> ! {
+    println!("{}", message);
+    std::process::exit(1);
+}
+
+fn run_llm_model(models: Arc<ModelRegistry>, gen_state: Arc<Mutex<GenerationState>>, job_queue: Arc<JobQueue>, tx: Sender<LlmServerMessage>) {
     thread::spawn(move || {
+        // prompts that come in while the models are loading wait in the queue
+        models.load_all();
+
         loop {
-            let block = rx.recv().unwrap();
-            match block {
-                LlmServerMessage::GeneratePrompt(prompt) => {
-                    println!("received prompt request: {}", prompt);
+            let job = job_queue.pop();
+            let lease = match models.runner(&job.model) {
+                Ok(lease) => lease,
+                Err(err) => {
+                    println!("prompt request {} from {} failed: {}", job.request_id, job.client, err.message);
+                    tx.send(LlmServerMessage::PromptDone(job.client, job.request_id, Err(err))).unwrap();
+                    continue;
+                },
+            };
+            println!("starting prompt request {} from {} on model {}: {}", job.request_id, job.client, job.model, job.prompt);
+
+            let mut gen_state_lock = gen_state.lock().unwrap();
+            gen_state_lock.is_generating = true;
+            // a cancel that arrived after the previous prompt was done must not stop this one
+            gen_state_lock.termination = None;
+            gen_state_lock.current_request = Some((job.client, job.request_id));
+            gen_state_lock.current_model = Some(job.model.clone());
+            gen_state_lock.generated_lines = Vec::new();
+            drop(gen_state_lock);
+
+            // an unload that cancels its model's prompt before the prompt was registered above can't stop it
+            if lease.is_cancelled() {
+                gen_state.lock().unwrap().termination = Some(TerminationCause::ModelUnloaded);
+            }
 
-                    let mut gen_state_lock = gen_state.lock().unwrap();
-                    gen_state_lock.is_generating = true;
-                    gen_state_lock.generated_lines = Vec::new();
-                    drop(gen_state_lock);
+            tx.send(LlmServerMessage::PromptStarted(job.client, job.request_id)).unwrap();
 
-                    // generate the thing!
-                    let gen_res = runner.run(prompt, Arc::clone(&gen_state));
+            // generate the thing!
+            let gen_res = lease.runner().lock().unwrap().run(job.prompt, job.params, job.chat_session, Arc::clone(&gen_state), |event| {
+                if job.stream {
+                    tx.send(LlmServerMessage::OutputGenerated(job.client, job.request_id, event)).unwrap();
+                }
+            }).map_err(|err| {
+                println!("prompt request {} from {} failed: {}", job.request_id, job.client, err);
+                ServerError::from(err)
+            });
+            // a model that is being unloaded is freed as soon as its last prompt is done
+            drop(lease);
 
-                    let mut gen_state_lock = gen_state.lock().unwrap();
-                    gen_state_lock.is_generating = false;
-                    drop(gen_state_lock);
+            let mut gen_state_lock = gen_state.lock().unwrap();
+            gen_state_lock.is_generating = false;
+            gen_state_lock.current_request = None;
+            gen_state_lock.current_model = None;
+            let termination = gen_state_lock.termination.take();
+            drop(gen_state_lock);
 
-                    tx.send(LlmServerMessage::PromptDone(gen_res)).unwrap();
+            // only the client's own request is answered as cancelled, it didn't ask for the unload
+            let gen_res = match gen_res {
+                Ok(results) if results.stop_reason == StopReason::Cancelled && termination == Some(TerminationCause::ModelUnloaded) => {
+                    Err(ServerError::new(ErrorCode::ModelUnavailable, format!("model {} was unloaded while generating", job.model)))
                 },
-                _ => {}
-            }
+                gen_res => gen_res,
+            };
+
+            tx.send(LlmServerMessage::PromptDone(job.client, job.request_id, gen_res)).unwrap();
         }
     });
 }
 
-fn run_server(gen_state: Arc<Mutex<GenerationState>>, rx: Receiver<LlmServerMessage>, tx: Sender<LlmServerMessage>) {
-    let (handler, node_listener) = node::split::<()>();
+/// State shared by the threads serving clients, whichever transport they are connected through.
+struct Server {
+    config: Arc<ServerConfig>,
+    models: Arc<ModelRegistry>,
+    handler: NodeHandler<()>,
+    unix_streams: Arc<UnixStreams>,
+    web_sockets: Arc<WebSockets>,
+    sessions: Mutex<ClientSessions>,
+    chat_sessions: Mutex<ChatSessions>,
+    gen_state: Arc<Mutex<GenerationState>>,
+    job_queue: Arc<JobQueue>,
+}
 
-    let listen_addr = dotenvy::var("LLM_SERVER_ADDR").unwrap();
-    handler.network().listen(Transport::FramedTcp, listen_addr.clone()).unwrap();
+impl Server {
+    fn send_message(&self, client: ClientId, message: &Message) {
+        let codec = self.sessions.lock().unwrap().get(&client).map_or_else(|| default_codec(client), |session| session.codec);
+        let output_data = match codec.encode(message) {
+            Ok(output_data) => output_data,
+            Err(err) => {
+                println!("unable to encode message for {} with {}: {}", client, codec.name(), err);
+                return;
+            },
+        };
+        match client {
+            ClientId::Net(endpoint) => {
+                self.handler.network().send(endpoint, &output_data);
+            },
+            ClientId::Unix(id) => self.unix_streams.send(id, &output_data),
+            ClientId::WebSocket(id) => self.web_sockets.send(id, &output_data, codec.is_text()),
+            ClientId::Http(_) => unreachable!("http requests are answered by the http server"),
+        }
+    }
 
-    println!("Llm server running at {}", listen_addr);
+    /// Close the connection of a client. This doesn't produce a disconnect event for message-io clients.
+    fn disconnect(&self, client: ClientId) {
+        self.sessions.lock().unwrap().remove(&client);
+        match client {
+            ClientId::Net(endpoint) => {
+                self.handler.network().remove(endpoint.resource_id());
+            },
+            ClientId::Unix(id) => self.unix_streams.remove(id),
+            ClientId::WebSocket(id) => self.web_sockets.remove(id),
+            ClientId::Http(_) => {},
+        }
+    }
 
-    let client_endpoint = Arc::new(Mutex::new(Option::<Endpoint>::None));
-    // set up the llm comm loop
-    let handler_llm_loop = handler.clone();
-    let client_endpoint_llm_loop = client_endpoint.clone();
-    let gen_state_llm_loop = gen_state.clone();
-    //tx.send(LlmServerMessage::GeneratePrompt("### Instruction: Write a conversation between characters of Penguins of Madagascar. You can only use these characters: Kowalski (acts as the group strategist and gadgeteer. Kowalski is a brilliant inventor, but he cannot read (although he does carry around a clipboard upon which he records drawings of their plans).), Rico (the team's weapons and explosives specialist, who mainly communicates through grunts and squeals, but sometimes he can speak rather normally. Slightly unhinged, Rico swallows useful tools, such as dynamite, and regurgitates them when needed, to the point of regularly regurgitating objects that appear to be too large for him to have swallowed in the first place), Private (is the emotionally sensitive rookie of the group. Though younger and less experienced than the other penguins, he is the most down to earth; Private tends to offer simpler, more commonsense solutions in response to Skipper and Kowalski's complex strategies). The penguins live in the Central Park Zoo in New York. Write more than 5 lines of dialogue. Topic: . ### Response:".to_string())).unwrap();
-    thread::spawn(move || {
-        loop {
-            let block = rx.recv().unwrap();
-            match block {
-                LlmServerMessage::PromptDone(gen_res) => {
-                    let client_endpoint_lock = client_endpoint_llm_loop.lock().unwrap();
-                    if client_endpoint_lock.is_none() {
-                        println!("client endpoint is none");
-                        return;
-                    }
+    /// Tell every client with a queued prompt where that prompt now is in the queue, if it agreed to be told.
+    fn notify_queue_positions(&self) {
+        for (client, request_id, position) in self.job_queue.positions() {
+            if self.has_capability(client, capabilities::QUEUE) {
+                self.send_message(client, &Message::QueuePositionChanged { request_id, position });
+            }
+        }
+    }
 
-                    let mut gen_state_lock = gen_state_llm_loop.lock().unwrap();
-                    gen_state_lock.is_generating = false;
-                    gen_state_lock.generated_lines = Vec::new();
+    /// Whether a client agreed on a capability during the handshake.
+    fn has_capability(&self, client: ClientId, capability: &str) -> bool {
+        self.sessions.lock().unwrap().get(&client).is_some_and(|session| session.has_capability(capability))
+    }
 
-                    if gen_res.was_terminated {
-                        return;
-                    }
+    /// Check the client speaks our protocol version and agree on the capabilities both sides support.
+    /// Incompatible clients are told why and disconnected.
+    fn handle_hello(&self, client: ClientId, protocol_version: u32, client_capabilities: Vec<String>, codec: String) {
+        if protocol_version != PROTOCOL_VERSION {
+            self.reject_hello(
+                client,
+                format!(
+                    "client protocol version {} is not supported, the server speaks version {}; update rust-llm-server-common",
+                    protocol_version, PROTOCOL_VERSION
+                ),
+            );
+            return;
+        }
+        let session_codec = self.sessions.lock().unwrap().get(&client).map_or_else(|| default_codec(client), |session| session.codec);
+        if codec != session_codec.name() {
+            self.reject_hello(client, format!("the Hello names the {:?} codec but was decoded with {}", codec, session_codec.name()));
+            return;
+        }
 
-                    let message = Message::GenerationDone(gen_res);
-                    let output_data = bincode::serialize(&message).unwrap();
-                    handler_llm_loop.network().send(client_endpoint_lock.unwrap(), &output_data);
-                },
-                _ => {}
+        // anyone who can connect to the Unix domain socket could run the server themselves, which isn't true for network clients
+        let is_admin_allowed = matches!(client, ClientId::Unix(_)) || self.config.admin.allow_network;
+        let capabilities: Vec<String> = client_capabilities
+            .into_iter()
+            .filter(|capability| SERVER_CAPABILITIES.contains(&capability.as_str()))
+            .filter(|capability| capability != capabilities::ADMIN || is_admin_allowed)
+            .collect();
+        println!("client {} completed handshake with capabilities {:?}", client, capabilities);
+
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&client) {
+            session.capabilities = Some(capabilities.clone());
+        }
+        self.send_message(client, &Message::Welcome { protocol_version: PROTOCOL_VERSION, capabilities, codec });
+    }
+
+    fn reject_hello(&self, client: ClientId, reason: String) {
+        println!("rejected client {}: {}", client, reason);
+        self.send_message(client, &Message::HelloRejected { protocol_version: PROTOCOL_VERSION, reason });
+        self.disconnect(client);
+    }
+
+    fn handle_connect(&self, client: ClientId) {
+        let mut sessions_lock = self.sessions.lock().unwrap();
+        sessions_lock.add(client);
+        println!("client {} connected ({} total)", client, sessions_lock.len());
+    }
+
+    fn decode_message(&self, client: ClientId, data: &[u8]) -> Result<Message, CodecError> {
+        let mut sessions_lock = self.sessions.lock().unwrap();
+        let Some(session) = sessions_lock.get_mut(&client) else {
+            return default_codec(client).decode(data);
+        };
+
+        // the client picks the codec of the connection by encoding its Hello with it
+        if !session.is_handshake_done() {
+            if let Some((codec, hello)) = decode_hello(data) {
+                println!("client {} uses the {} codec", client, codec.name());
+                session.codec = codec;
+                return Ok(hello);
             }
         }
-    });
+        session.codec.decode(data)
+    }
 
-    // set up the msg receiving loop
-    let handler_server_loop = handler.clone();
-    let client_endpoint_server_loop = client_endpoint.clone();
-    let gen_state_server_loop = gen_state.clone();
-    thread::spawn(move || {
-        node_listener.for_each(move |event| match event.network() {
-            NetEvent::Accepted(endpoint, _) => {
-                let mut client_endpoint_lock = client_endpoint_server_loop.lock().unwrap();
-                let _ = std::mem::replace(&mut *client_endpoint_lock, Some(endpoint));
-                println!("client connected");
+    fn handle_message(self: &Arc<Self>, client: ClientId, data: &[u8]) {
+        let message = match self.decode_message(client, data) {
+            Ok(message) => message,
+            // the Hello of a client built against an incompatible version may not decode at all
+            Err(err) if !self.sessions.lock().unwrap().get(&client).is_some_and(|session| session.is_handshake_done()) => {
+                self.reject_hello(client, format!("unable to decode the Hello: {}; update rust-llm-server-common", err));
+                return;
+            },
+            Err(err) => {
+                println!("received malformed message from {}: {}", client, err);
+                let error = ServerError::new(ErrorCode::MalformedMessage, format!("unable to decode message: {}", err));
+                self.send_message(client, &error.into_message(None));
+                return;
+            },
+        };
+        let agreed_capabilities = self.sessions.lock().unwrap().get(&client).and_then(|session| session.capabilities.clone());
+        if let Message::Hello { protocol_version, capabilities, codec } = message {
+            if agreed_capabilities.is_some() {
+                println!("client {} sent a second Hello", client);
+                let error = ServerError::new(ErrorCode::UnexpectedMessage, "the handshake is done already");
+                self.send_message(client, &error.into_message(None));
+                return;
             }
-            NetEvent::Message(endpoint, data) => {
-                let message: Message = bincode::deserialize(&data).unwrap();
-                match message {
-                    Message::GeneratePrompt(prompt_str) => {
-                        let gen_state_lock = gen_state_server_loop.lock().unwrap();
-                        if gen_state_lock.is_generating {
-                            if gen_state_lock.should_terminate {
-                                println!("still terminating previous prompt")
-                            } else {
-                                println!("unable to generate new prompt: still generating");
-                            }
-                            return;
-                        }
-
-                        tx.send(LlmServerMessage::GeneratePrompt(prompt_str)).unwrap();
-                    },
-                    Message::RequestCurrentGeneratedLines => {
-                        let gen_state_lock = gen_state.lock().unwrap();
+            self.handle_hello(client, protocol_version, capabilities, codec);
+            return;
+        }
 
-                        let message = Message::CurrentGeneratedLinesResponse(gen_state_lock.generated_lines.clone());
-                        let output_data = bincode::serialize(&message).unwrap();
-                        handler_server_loop.network().send(endpoint, &output_data);
-                    },
-                    _ => {
-                        println!("unexpected message type received")
+        let Some(agreed_capabilities) = agreed_capabilities else {
+            println!("client {} sent a request before the handshake", client);
+            let error = ServerError::new(ErrorCode::HandshakeRequired, "send a Hello message before any other request");
+            self.send_message(client, &error.into_message(None));
+            return;
+        };
+        let missing_capability = required_capabilities(&message)
+            .into_iter()
+            .find(|capability| !agreed_capabilities.iter().any(|agreed| agreed == capability));
+        if let Some(capability) = missing_capability {
+            println!("client {} sent a request that needs the {} capability", client, capability);
+            let error = ServerError::new(ErrorCode::UnsupportedCapability, format!("the {} capability wasn't agreed on during the handshake", capability));
+            self.send_message(client, &error.into_message(message.request_id()));
+            return;
+        }
+
+        match message {
+            Message::GeneratePrompt { request_id, prompt, stream, params, model } => {
+                let reply = self.build_job(client, request_id, PromptInput::Text(prompt), stream, params, model).and_then(|job| self.queue_prompt(job));
+                self.reply(client, request_id, reply);
+            },
+            Message::GenerateChat { request_id, messages, stream, params, model } => {
+                if messages.is_empty() {
+                    let error = ServerError::new(ErrorCode::InvalidParams, "messages must not be empty");
+                    self.send_message(client, &error.into_message(Some(request_id)));
+                    return;
+                }
+                let reply = self.build_job(client, request_id, PromptInput::Chat(messages), stream, params, model).and_then(|job| self.queue_prompt(job));
+                self.reply(client, request_id, reply);
+            },
+            Message::RequestCurrentGeneratedLines { request_id } => {
+                let gen_state_lock = self.gen_state.lock().unwrap();
+
+                // only the request that is currently running has any output to show
+                let lines = if gen_state_lock.current_request == Some((client, request_id)) {
+                    gen_state_lock.generated_lines.clone()
+                } else {
+                    Vec::new()
+                };
+                self.send_message(client, &Message::CurrentGeneratedLinesResponse { request_id, lines });
+            },
+            Message::CancelGeneration { request_id } => {
+                if self.job_queue.remove(client, request_id).is_some() {
+                    println!("cancelled queued prompt request {} from {}", request_id, client);
+                    self.chat_sessions.lock().unwrap().finish_reply(client, request_id, None);
+                    if let Some(session) = self.sessions.lock().unwrap().get_mut(&client) {
+                        session.pending_requests.remove(&request_id);
                     }
+
+                    let results = GenerationResults::terminated_before_start();
+                    self.send_message(client, &Message::GenerationCancelled { request_id, results });
+                    self.notify_queue_positions();
+                    return;
                 }
-            }
-            NetEvent::Disconnected(_endpoint) => {
-                let mut gen_state_lock = gen_state.lock().unwrap();
-                if gen_state_lock.is_generating {
-                    println!("client disconnected, terminating current text gen");
-                    gen_state_lock.should_terminate = true;
+
+                // the reply is sent by the llm comm loop once the runner has stopped
+                let mut gen_state_lock = self.gen_state.lock().unwrap();
+                if gen_state_lock.is_generating && gen_state_lock.current_request == Some((client, request_id)) {
+                    println!("cancelling prompt request {} from {}", request_id, client);
+                    gen_state_lock.termination = Some(TerminationCause::Cancelled);
                 } else {
-                    println!("client disconnected");
+                    println!("client {} tried to cancel unknown prompt request {}", client, request_id);
+                    let error = ServerError::new(ErrorCode::UnknownRequest, format!("no pending prompt request with id {}", request_id));
+                    self.send_message(client, &error.into_message(Some(request_id)));
                 }
+            },
+            Message::ListModels { request_id } => {
+                self.send_message(client, &Message::ModelList { request_id, models: self.models.list() });
+            },
+            Message::CreateChatSession { request_id, model, system_prompt } => {
+                let reply = self.models.select(model.as_deref()).map(|model| {
+                    let session_id = self.chat_sessions.lock().unwrap().create(model.name, system_prompt);
+                    println!("client {} created chat session {}", client, session_id);
+                    Message::ChatSessionCreated { request_id, session_id }
+                });
+                self.reply(client, request_id, reply);
+            },
+            Message::AppendUserMessage { request_id, session_id, content } => {
+                let reply = self.chat_sessions.lock().unwrap().get_mut(session_id).and_then(|session| {
+                    session.check_not_busy(session_id)?;
+                    session.messages.push(ChatMessage { role: ChatRole::User, content });
+                    Ok(Message::UserMessageAppended { request_id, session_id })
+                });
+                self.reply(client, request_id, reply);
+            },
+            Message::GenerateChatReply { request_id, session_id, stream, params } => {
+                let reply = self.handle_chat_reply(client, request_id, session_id, stream, params);
+                self.reply(client, request_id, reply);
+            },
+            Message::GetChatHistory { request_id, session_id } => {
+                let reply = self.chat_sessions.lock().unwrap().get(session_id).map(|session| Message::ChatHistory {
+                    request_id,
+                    session_id,
+                    messages: session.messages.clone(),
+                });
+                self.reply(client, request_id, reply);
+            },
+            Message::DeleteChatSession { request_id, session_id } => {
+                let reply = self.chat_sessions.lock().unwrap().remove(session_id).map(|_| Message::ChatSessionDeleted { request_id, session_id });
+                self.reply(client, request_id, reply);
+            },
+            Message::LoadModel { .. } | Message::UnloadModel { .. } | Message::ReloadModel { .. } => {
+                self.handle_admin_message(client, message);
+            },
+            _ => {
+                println!("unexpected message type received from {}", client);
+                let error = ServerError::new(ErrorCode::UnexpectedMessage, "only requests can be sent to the server");
+                self.send_message(client, &error.into_message(None));
             }
+        }
+    }
+
+    /// Check a prompt request of a client and turn it into a job for the model it asked for.
+    fn build_job(&self, client: ClientId, request_id: RequestId, input: PromptInput, stream: bool, params: Option<GenerationParams>, model: Option<String>) -> Result<Job, ServerError> {
+        let model = self.models.select(model.as_deref())?;
+        let mut params = resolve_params(params, &self.config.generation, model.context_size)
+            .map_err(|reason| ServerError::new(ErrorCode::InvalidParams, reason))?;
+
+        let (prompt, stop_sequences) = input.render(model.chat_template);
+        params.generator_params.stop_sequences.extend(stop_sequences);
+        Ok(Job { client, request_id, prompt, model: model.name, chat_session: None, stream, params })
+    }
+
+    /// Queue the reply to the last message of a chat session. The session is marked as busy until the
+    /// llm runner is done with it, so that its history can't change in the meantime.
+    fn handle_chat_reply(&self, client: ClientId, request_id: RequestId, session_id: ChatSessionId, stream: bool, params: Option<GenerationParams>) -> Result<Option<Message>, ServerError> {
+        let (model, messages) = {
+            let mut chat_sessions_lock = self.chat_sessions.lock().unwrap();
+            let session = chat_sessions_lock.get_mut(session_id)?;
+            session.check_not_busy(session_id)?;
+            if !session.messages.last().is_some_and(|message| message.role == ChatRole::User) {
+                return Err(ServerError::new(ErrorCode::InvalidParams, format!("chat session {} has no user message to reply to", session_id)));
+            }
+
+            session.pending_reply = Some((client, request_id));
+            (session.model.clone(), session.messages.clone())
+        };
+
+        let reply = self
+            .build_job(client, request_id, PromptInput::Chat(messages), stream, params, Some(model))
+            .and_then(|job| self.queue_prompt(Job { chat_session: Some(session_id), ..job }));
+        if reply.is_err() {
+            self.chat_sessions.lock().unwrap().finish_reply(client, request_id, None);
+        }
+        reply
+    }
+
+    /// Send the reply to a request if there is one, logging it if the request failed.
+    fn reply(&self, client: ClientId, request_id: RequestId, reply: Result<impl Into<Option<Message>>, ServerError>) {
+        let message = match reply {
+            Ok(message) => message.into(),
+            Err(err) => {
+                println!("request {} from {} failed: {}", request_id, client, err.message);
+                Some(err.into_message(Some(request_id)))
+            },
+        };
+        if let Some(message) = message {
+            self.send_message(client, &message);
+        }
+    }
+
+    /// Add a prompt of a client to the job queue, returning the reply to the client. Clients that
+    /// didn't agree on the queue capability aren't told about the queue, they only get the results.
+    fn queue_prompt(&self, job: Job) -> Result<Option<Message>, ServerError> {
+        let (client, request_id) = (job.client, job.request_id);
+        let mut sessions_lock = self.sessions.lock().unwrap();
+        let Some(session) = sessions_lock.get_mut(&client) else {
+            return Err(ServerError::new(ErrorCode::UnexpectedMessage, "the client is not connected"));
+        };
+        if !session.pending_requests.insert(request_id) {
+            println!("client {} reused request id {} of a pending request", client, request_id);
+            return Err(ServerError::new(ErrorCode::DuplicateRequestId, format!("request id {} is already in use", request_id)));
+        }
+
+        match self.job_queue.push(job) {
+            Some(position) => Ok(session.has_capability(capabilities::QUEUE).then_some(Message::GenerationQueued { request_id, position })),
+            None => {
+                println!("rejected prompt request {} from {}: queue is full", request_id, client);
+                session.pending_requests.remove(&request_id);
+
+                let reason = format!("the job queue is full ({} prompts waiting), try again later", self.job_queue.capacity());
+                Err(ServerError::new(ErrorCode::QueueFull, reason))
+            },
+        }
+    }
+
+    /// Carry out a request to load or unload a model and report the outcome. Loading a model or waiting
+    /// for the prompt running on it takes a while, so this is done on a thread of its own.
+    fn handle_admin_message(self: &Arc<Self>, client: ClientId, message: Message) {
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            let (request_id, reply) = match message {
+                Message::LoadModel { request_id, name, path, context_size, gpu_layers, chat_template } => {
+                    println!("client {} loads model {}", client, name);
+                    let reply = chat_template
+                        .map(|chat_template| chat_template.parse::<ChatTemplate>())
+                        .transpose()
+                        .map_err(|reason| ServerError::new(ErrorCode::InvalidParams, reason))
+                        .and_then(|chat_template| server.models.load(&name, path.map(PathBuf::from), context_size, gpu_layers, chat_template));
+                    (request_id, reply.map(|model| Message::ModelLoaded { request_id, model }))
+                },
+                Message::UnloadModel { request_id, name, cancel } => {
+                    println!("client {} unloads model {}", client, name);
+                    let reply = server.unload_model(&name, cancel, false);
+                    (request_id, reply.map(|_| Message::ModelUnloaded { request_id, name }))
+                },
+                Message::ReloadModel { request_id, name, cancel } => {
+                    println!("client {} reloads model {}", client, name);
+                    let reply = server.unload_model(&name, cancel, true);
+                    (request_id, reply.map(|model| Message::ModelLoaded { request_id, model: model.expect("a reloaded model has info") }))
+                },
+                _ => unreachable!("only admin requests are handled here"),
+            };
+            server.send_message(client, &reply.unwrap_or_else(|err| err.into_message(Some(request_id))));
+        });
+    }
+
+    /// Unload a model once the prompt running on it is done, or cancel that prompt first. With `reload`
+    /// set the model is loaded again afterwards, returning its info.
+    fn unload_model(&self, name: &str, cancel: bool, reload: bool) -> Result<Option<ModelInfo>, ServerError> {
+        self.models.begin_unload(name, reload, cancel)?;
+        if cancel {
+            let mut gen_state_lock = self.gen_state.lock().unwrap();
+            if gen_state_lock.is_generating && gen_state_lock.current_model.as_deref() == Some(name) {
+                println!("cancelling the running prompt of model {}", name);
+                gen_state_lock.termination = Some(TerminationCause::ModelUnloaded);
+            }
+        }
+
+        self.models.wait_until_unused(name);
+        self.models.finish_unload(name)
+    }
+
+    fn handle_disconnect(&self, client: ClientId) {
+        self.sessions.lock().unwrap().remove(&client);
+
+        let dropped_jobs = self.job_queue.remove_client(client);
+        if !dropped_jobs.is_empty() {
+            println!("dropped {} queued prompts of client {}", dropped_jobs.len(), client);
+            let mut chat_sessions_lock = self.chat_sessions.lock().unwrap();
+            for job in &dropped_jobs {
+                chat_sessions_lock.finish_reply(client, job.request_id, None);
+            }
+            drop(chat_sessions_lock);
+            self.notify_queue_positions();
+        }
+
+        let mut gen_state_lock = self.gen_state.lock().unwrap();
+        let is_client_request = matches!(gen_state_lock.current_request, Some((current_client, _)) if current_client == client);
+        if gen_state_lock.is_generating && is_client_request {
+            println!("client {} disconnected, terminating its text gen", client);
+            gen_state_lock.termination = Some(TerminationCause::Cancelled);
+        } else {
+            println!("client {} disconnected", client);
+        }
+    }
+
+    /// Pass what the llm runner reports about a prompt on to the client that sent it.
+    fn handle_llm_message(&self, block: LlmServerMessage) {
+        match block {
+            LlmServerMessage::PromptStarted(client, request_id) => {
+                if self.has_capability(client, capabilities::QUEUE) {
+                    self.send_message(client, &Message::GenerationStarted { request_id });
+                }
+                // every prompt behind the started one moved up the queue
+                self.notify_queue_positions();
+            },
+            LlmServerMessage::OutputGenerated(client, request_id, event) => {
+                let message = match event {
+                    GenerationEvent::Token(token) => Message::TokenGenerated { request_id, token },
+                    GenerationEvent::Line(line_index, line) => Message::LineGenerated { request_id, line_index, line },
+                };
+                self.send_message(client, &message);
+            },
+            LlmServerMessage::PromptDone(client, request_id, gen_res) => {
+                // chat sessions outlive the connection, so the reply is kept even if the client is gone
+                let chat_reply = match &gen_res {
+                    Ok(results) if results.stop_reason != StopReason::Cancelled => Some(results.full_generated_text.trim().to_string()),
+                    _ => None,
+                };
+                self.chat_sessions.lock().unwrap().finish_reply(client, request_id, chat_reply);
+
+                match self.sessions.lock().unwrap().get_mut(&client) {
+                    Some(session) => {
+                        session.pending_requests.remove(&request_id);
+                    },
+                    None => {
+                        println!("client {} disconnected before its prompt was done", client);
+                        return;
+                    },
+                }
+
+                // the client is still connected, so a cancelled prompt must have been stopped by its CancelGeneration,
+                // prompts stopped by unloading their model were turned into errors by the runner thread
+                let message = match gen_res {
+                    Ok(results) if results.stop_reason == StopReason::Cancelled => Message::GenerationCancelled { request_id, results },
+                    Ok(results) => Message::GenerationDone { request_id, results },
+                    Err(err) => err.into_message(Some(request_id)),
+                };
+                self.send_message(client, &message);
+            },
+        }
+    }
+}
+
+/// The capabilities a request relies on, each of which the client must have agreed on during the handshake.
+fn required_capabilities(message: &Message) -> Vec<&'static str> {
+    let generation_capabilities = |stream: bool, params: &Option<GenerationParams>| {
+        let mut required = Vec::new();
+        if stream {
+            required.push(capabilities::STREAMING);
+        }
+        if params.is_some() {
+            required.push(capabilities::GENERATION_PARAMS);
+        }
+        required
+    };
+
+    match message {
+        Message::GeneratePrompt { stream, params, model, .. } => {
+            let mut required = generation_capabilities(*stream, params);
+            required.extend(model.as_ref().map(|_| capabilities::MODELS));
+            required
+        },
+        Message::GenerateChat { stream, params, model, .. } => {
+            let mut required = vec![capabilities::CHAT];
+            required.extend(generation_capabilities(*stream, params));
+            required.extend(model.as_ref().map(|_| capabilities::MODELS));
+            required
+        },
+        Message::CancelGeneration { .. } => vec![capabilities::CANCEL],
+        Message::ListModels { .. } => vec![capabilities::MODELS],
+        Message::CreateChatSession { model, .. } => {
+            let mut required = vec![capabilities::CHAT_SESSIONS];
+            required.extend(model.as_ref().map(|_| capabilities::MODELS));
+            required
+        },
+        Message::GenerateChatReply { stream, params, .. } => {
+            let mut required = vec![capabilities::CHAT_SESSIONS];
+            required.extend(generation_capabilities(*stream, params));
+            required
+        },
+        Message::AppendUserMessage { .. } | Message::GetChatHistory { .. } | Message::DeleteChatSession { .. } => vec![capabilities::CHAT_SESSIONS],
+        Message::LoadModel { .. } | Message::UnloadModel { .. } | Message::ReloadModel { .. } => vec![capabilities::ADMIN],
+        _ => Vec::new(),
+    }
+}
+
+fn run_server(
+    config: Arc<ServerConfig>,
+    models: Arc<ModelRegistry>,
+    gen_state: Arc<Mutex<GenerationState>>,
+    job_queue: Arc<JobQueue>,
+    pending_http_requests: Arc<PendingHttpRequests>,
+    rx: Receiver<LlmServerMessage>,
+) {
+    let (handler, node_listener) = node::split::<()>();
+    let unix_streams = Arc::new(UnixStreams::default());
+    let web_sockets = Arc::new(WebSockets::default());
+    let listeners = &config.listeners;
+    let server = Arc::new(Server {
+        config: config.clone(),
+        models,
+        handler: handler.clone(),
+        unix_streams: unix_streams.clone(),
+        web_sockets: web_sockets.clone(),
+        sessions: Mutex::new(ClientSessions::default()),
+        chat_sessions: Mutex::new(ChatSessions::new(Duration::from_secs(config.chat_sessions.idle_timeout_secs))),
+        gen_state,
+        job_queue,
+    });
+
+    // clients on the same machine can use a Unix domain socket, which other users can be locked out of
+    if let Some(socket_path) = &listeners.unix_socket {
+        let server_unix_loop = server.clone();
+        listen_unix_socket(socket_path, listeners.unix_socket_mode, unix_streams, move |event| match event {
+            UnixSocketEvent::Accepted(id) => server_unix_loop.handle_connect(ClientId::Unix(id)),
+            UnixSocketEvent::Message(id, data) => server_unix_loop.handle_message(ClientId::Unix(id), &data),
+            UnixSocketEvent::Disconnected(id) => server_unix_loop.handle_disconnect(ClientId::Unix(id)),
+        })
+        .unwrap_or_else(|err| exit_with_error(format!("unable to listen on {}: {}", socket_path.display(), err)));
+
+        println!("Llm server running at {} (mode {:o})", socket_path.display(), listeners.unix_socket_mode);
+    }
+
+    if let Some(listen_addr) = &listeners.tcp {
+        handler
+            .network()
+            .listen(Transport::FramedTcp, listen_addr.as_str())
+            .unwrap_or_else(|err| exit_with_error(format!("unable to listen on {}: {}", listen_addr, err)));
+
+        println!("Llm server running at {}", listen_addr);
+    }
+
+    // browser based tools can't speak framed TCP, they get the same protocol as JSON over a WebSocket
+    if let Some(ws_listen_addr) = &listeners.websocket {
+        let server_ws_loop = server.clone();
+        listen_websocket(ws_listen_addr, web_sockets, move |event| match event {
+            WebSocketEvent::Accepted(id) => server_ws_loop.handle_connect(ClientId::WebSocket(id)),
+            WebSocketEvent::Message(id, data) => server_ws_loop.handle_message(ClientId::WebSocket(id), &data),
+            WebSocketEvent::Disconnected(id) => server_ws_loop.handle_disconnect(ClientId::WebSocket(id)),
+        })
+        .unwrap_or_else(|err| exit_with_error(format!("unable to listen on {}: {}", ws_listen_addr, err)));
+
+        println!("Llm websocket server running at {}", ws_listen_addr);
+    }
+
+    // set up the llm comm loop
+    let server_llm_loop = server.clone();
+    //tx.send(LlmServerMessage::GeneratePrompt("### Instruction: Write a conversation between characters of Penguins of Madagascar. You can only use these characters: Kowalski (acts as the group strategist and gadgeteer. Kowalski is a brilliant inventor, but he cannot read (although he does carry around a clipboard upon which he records drawings of their plans).), Rico (the team's weapons and explosives specialist, who mainly communicates through grunts and squeals, but sometimes he can speak rather normally. Slightly unhinged, Rico swallows useful tools, such as dynamite, and regurgitates them when needed, to the point of regularly regurgitating objects that appear to be too large for him to have swallowed in the first place), Private (is the emotionally sensitive rookie of the group. Though younger and less experienced than the other penguins, he is the most down to earth; Private tends to offer simpler, more commonsense solutions in response to Skipper and Kowalski's complex strategies). The penguins live in the Central Park Zoo in New York. Write more than 5 lines of dialogue. Topic: . ### Response:".to_string())).unwrap();
+    thread::spawn(move || {
+        loop {
+            let block = rx.recv().unwrap();
+            match block.client() {
+                ClientId::Http(id) => pending_http_requests.forward(id, block),
+                _ => server_llm_loop.handle_llm_message(block),
+            }
+        }
+    });
+
+    // set up the msg receiving loop
+    thread::spawn(move || {
+        node_listener.for_each(move |event| match event.network() {
+            NetEvent::Accepted(endpoint, _) => server.handle_connect(ClientId::Net(endpoint)),
+            NetEvent::Message(endpoint, data) => server.handle_message(ClientId::Net(endpoint), data),
+            NetEvent::Disconnected(endpoint) => server.handle_disconnect(ClientId::Net(endpoint)),
             _ => {}
         })
     });
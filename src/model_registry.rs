@@ -0,0 +1,312 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use rust_llm_server_common::{ErrorCode, ModelInfo, ModelStatus};
+
+use crate::chat_template::ChatTemplate;
+use crate::config::{ModelConfig, ServerConfig};
+use crate::llm_runner_diff_backend::LlmRunner;
+use crate::server_error::ServerError;
+
+enum LoadState {
+    Loading,
+    Loaded(Arc<Mutex<LlmRunner>>),
+    Failed(String),
+    /// Waiting for the prompt running on the model, `reload` meaning it's loaded again afterwards
+    /// and `cancel` that the prompt is to be stopped
+    Unloading { reload: bool, cancel: bool },
+    Unloaded,
+}
+
+struct RegisteredModel {
+    name: String,
+    config: ModelConfig,
+    state: LoadState,
+    /// Whether a prompt holds the runner. Only changed with the registry locked, so that an unload
+    /// can't miss a prompt that is about to start.
+    in_use: bool,
+}
+
+impl RegisteredModel {
+    fn info(&self, is_default: bool) -> ModelInfo {
+        ModelInfo {
+            name: self.name.clone(),
+            context_size: self.config.context_size,
+            status: match &self.state {
+                LoadState::Loading => ModelStatus::Loading,
+                LoadState::Loaded(_) => ModelStatus::Loaded,
+                LoadState::Failed(reason) => ModelStatus::Failed { reason: reason.clone() },
+                LoadState::Unloading { .. } => ModelStatus::Unloading,
+                LoadState::Unloaded => ModelStatus::Unloaded,
+            },
+            is_default,
+        }
+    }
+
+    fn unavailable(&self) -> ServerError {
+        let reason = match &self.state {
+            LoadState::Loading => "is being loaded",
+            LoadState::Unloading { .. } => "is being unloaded",
+            _ => "was unloaded",
+        };
+        ServerError::new(ErrorCode::ModelUnavailable, format!("model {} {}", self.name, reason))
+    }
+}
+
+/// Every configured model by name, along with whether it could be loaded.
+pub(crate) struct ModelRegistry {
+    /// In the order they were registered in, the default model first
+    models: Mutex<Vec<RegisteredModel>>,
+    /// Notified whenever a model is done loading or unloading
+    state_changed: Condvar,
+}
+
+/// A model a prompt was checked to be allowed to use.
+pub(crate) struct SelectedModel {
+    pub(crate) name: String,
+    pub(crate) context_size: usize,
+    pub(crate) chat_template: ChatTemplate,
+}
+
+impl ModelRegistry {
+    /// Register the models of the config, none of them is loaded until `load_all` is called.
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        let models = config
+            .model_configs()
+            .into_iter()
+            .map(|(name, config)| RegisteredModel {
+                name,
+                config: config.clone(),
+                state: LoadState::Loading,
+                in_use: false,
+            })
+            .collect();
+        Self {
+            models: Mutex::new(models),
+            state_changed: Condvar::new(),
+        }
+    }
+
+    /// Load every model one after the other. Models that fail are reported to the clients that ask
+    /// for them instead of stopping the server.
+    pub(crate) fn load_all(&self) {
+        let names: Vec<String> = self.models.lock().unwrap().iter().map(|model| model.name.clone()).collect();
+        for name in names {
+            // the failure was logged already, and there's no one to report it to
+            let _ = self.load_registered(&name);
+        }
+    }
+
+    /// Load a model under a new name, or an unloaded or failed model again, optionally with new settings.
+    pub(crate) fn load(
+        &self,
+        name: &str,
+        path: Option<PathBuf>,
+        context_size: Option<usize>,
+        gpu_layers: Option<i32>,
+        chat_template: Option<ChatTemplate>,
+    ) -> Result<ModelInfo, ServerError> {
+        let mut models = self.models.lock().unwrap();
+        let index = models.iter().position(|model| model.name == name);
+        let mut config = match index {
+            Some(index) => {
+                let model = &models[index];
+                match model.state {
+                    LoadState::Failed(_) | LoadState::Unloaded => model.config.clone(),
+                    LoadState::Loaded(_) => {
+                        return Err(ServerError::new(ErrorCode::ModelUnavailable, format!("model {} is loaded already, reload it instead", name)));
+                    },
+                    _ => return Err(model.unavailable()),
+                }
+            },
+            None if path.is_some() => ModelConfig::default(),
+            None => return Err(ServerError::new(ErrorCode::InvalidParams, format!("there is no model named {}, a path is needed to load it", name))),
+        };
+
+        config.path = path.unwrap_or(config.path);
+        config.context_size = context_size.unwrap_or(config.context_size);
+        config.gpu_layers = gpu_layers.unwrap_or(config.gpu_layers);
+        config.chat_template = chat_template.unwrap_or(config.chat_template);
+        config.validate("model").map_err(|err| ServerError::new(ErrorCode::InvalidParams, err.to_string()))?;
+
+        match index {
+            Some(index) => {
+                models[index].config = config;
+                models[index].state = LoadState::Loading;
+            },
+            None => models.push(RegisteredModel {
+                name: name.to_string(),
+                config,
+                state: LoadState::Loading,
+                in_use: false,
+            }),
+        }
+        drop(models);
+
+        self.load_registered(name)
+    }
+
+    /// Load a registered model with its current settings, it must have been marked as loading.
+    fn load_registered(&self, name: &str) -> Result<ModelInfo, ServerError> {
+        let config = {
+            let models = self.models.lock().unwrap();
+            let Some(model) = models.iter().find(|model| model.name == name) else {
+                return Err(ServerError::new(ErrorCode::UnknownModel, format!("there is no model named {}", name)));
+            };
+            model.config.clone()
+        };
+
+        // the lock isn't held while loading, so that the models can be listed and used in the meantime
+        println!("loading model {} from {}", name, config.path.display());
+        let (state, result) = match LlmRunner::new(&config) {
+            Ok(runner) => {
+                println!("model {} loaded", name);
+                (LoadState::Loaded(Arc::new(Mutex::new(runner))), Ok(()))
+            },
+            Err(err) => {
+                println!("unable to load model {}: {}", name, err);
+                (LoadState::Failed(err.to_string()), Err(ServerError::from(err)))
+            },
+        };
+
+        let mut models = self.models.lock().unwrap();
+        let index = models.iter().position(|model| model.name == name).unwrap();
+        models[index].state = state;
+        self.state_changed.notify_all();
+        result.map(|_| models[index].info(index == 0))
+    }
+
+    /// Mark a model as being unloaded, so that no more prompts are started on it. The caller then waits
+    /// for the prompt running on it with `wait_until_unused`, which is asked to stop if `cancel` is set.
+    /// With `reload` set, prompts for the model keep being accepted and wait until it is loaded again.
+    pub(crate) fn begin_unload(&self, name: &str, reload: bool, cancel: bool) -> Result<(), ServerError> {
+        let mut models = self.models.lock().unwrap();
+        let Some(model) = models.iter_mut().find(|model| model.name == name) else {
+            return Err(ServerError::new(ErrorCode::UnknownModel, format!("there is no model named {}", name)));
+        };
+
+        match model.state {
+            // the runner is freed once the prompt holding it is done with it
+            LoadState::Loaded(_) | LoadState::Failed(_) => {
+                model.state = LoadState::Unloading { reload, cancel };
+                Ok(())
+            },
+            _ => Err(model.unavailable()),
+        }
+    }
+
+    /// Block until no prompt holds the runner of a model anymore.
+    pub(crate) fn wait_until_unused(&self, name: &str) {
+        let mut models = self.models.lock().unwrap();
+        while models.iter().any(|model| model.name == name && model.in_use) {
+            models = self.state_changed.wait(models).unwrap();
+        }
+    }
+
+    /// Finish unloading a model once nothing uses its runner anymore. A model that is being reloaded
+    /// is loaded again right away, returning its info once it is.
+    pub(crate) fn finish_unload(&self, name: &str) -> Result<Option<ModelInfo>, ServerError> {
+        let mut models = self.models.lock().unwrap();
+        let Some(model) = models.iter_mut().find(|model| model.name == name) else {
+            return Err(ServerError::new(ErrorCode::UnknownModel, format!("there is no model named {}", name)));
+        };
+        let reload = matches!(model.state, LoadState::Unloading { reload: true, .. });
+        model.state = if reload { LoadState::Loading } else { LoadState::Unloaded };
+        self.state_changed.notify_all();
+        println!("model {} unloaded", name);
+        if !reload {
+            return Ok(None);
+        }
+
+        drop(models);
+        self.load_registered(name).map(Some)
+    }
+
+    /// Look up the model a prompt asked for, `None` meaning the default model. Models that are
+    /// still loading or being reloaded can be selected, their prompts wait until they are done.
+    pub(crate) fn select(&self, name: Option<&str>) -> Result<SelectedModel, ServerError> {
+        let models = self.models.lock().unwrap();
+        let model = match name {
+            Some(name) => models
+                .iter()
+                .find(|model| model.name == name)
+                .ok_or_else(|| ServerError::new(ErrorCode::UnknownModel, format!("there is no model named {}", name)))?,
+            None => &models[0],
+        };
+
+        match &model.state {
+            LoadState::Loading | LoadState::Loaded(_) | LoadState::Unloading { reload: true, .. } => Ok(SelectedModel {
+                name: model.name.clone(),
+                context_size: model.config.context_size,
+                chat_template: model.config.chat_template,
+            }),
+            LoadState::Failed(reason) => Err(ServerError::new(ErrorCode::ModelLoadFailed, format!("model {} couldn't be loaded: {}", model.name, reason))),
+            LoadState::Unloading { reload: false, .. } | LoadState::Unloaded => Err(model.unavailable()),
+        }
+    }
+
+    /// The runner of a model, for the llm runner thread to generate with. Blocks while the model is
+    /// loading or being reloaded. The model can't finish unloading until the returned lease is dropped.
+    pub(crate) fn runner(&self, name: &str) -> Result<RunnerLease<'_>, ServerError> {
+        let mut models = self.models.lock().unwrap();
+        loop {
+            let Some(model) = models.iter_mut().find(|model| model.name == name) else {
+                return Err(ServerError::new(ErrorCode::UnknownModel, format!("there is no model named {}", name)));
+            };
+            match &model.state {
+                LoadState::Loaded(runner) => {
+                    model.in_use = true;
+                    return Ok(RunnerLease {
+                        registry: self,
+                        name: name.to_string(),
+                        runner: Arc::clone(runner),
+                    });
+                },
+                LoadState::Loading | LoadState::Unloading { reload: true, .. } => {},
+                LoadState::Failed(reason) => {
+                    return Err(ServerError::new(ErrorCode::ModelLoadFailed, format!("model {} couldn't be loaded: {}", name, reason)));
+                },
+                LoadState::Unloading { reload: false, .. } | LoadState::Unloaded => return Err(model.unavailable()),
+            }
+            models = self.state_changed.wait(models).unwrap();
+        }
+    }
+
+    fn release(&self, name: &str) {
+        let mut models = self.models.lock().unwrap();
+        if let Some(model) = models.iter_mut().find(|model| model.name == name) {
+            model.in_use = false;
+        }
+        self.state_changed.notify_all();
+    }
+
+    pub(crate) fn list(&self) -> Vec<ModelInfo> {
+        let models = self.models.lock().unwrap();
+        models.iter().enumerate().map(|(i, model)| model.info(i == 0)).collect()
+    }
+}
+
+/// The runner of a model, held by the prompt running on it.
+pub(crate) struct RunnerLease<'a> {
+    registry: &'a ModelRegistry,
+    name: String,
+    runner: Arc<Mutex<LlmRunner>>,
+}
+
+impl RunnerLease<'_> {
+    pub(crate) fn runner(&self) -> &Mutex<LlmRunner> {
+        &self.runner
+    }
+
+    /// Whether the model started being unloaded with its prompt cancelled. A prompt checks this once it
+    /// is registered as running, as the unload may have come too early to find it.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        let models = self.registry.models.lock().unwrap();
+        models.iter().any(|model| model.name == self.name && matches!(model.state, LoadState::Unloading { cancel: true, .. }))
+    }
+}
+
+impl Drop for RunnerLease<'_> {
+    fn drop(&mut self) {
+        self.registry.release(&self.name);
+    }
+}
@@ -0,0 +1,153 @@
+use rust_llm_server_common::{ChatMessage, ChatRole};
+use serde::{Deserialize, Serialize};
+
+/// Body of `POST /v1/completions`.
+#[derive(Deserialize)]
+pub(crate) struct CompletionRequest {
+    pub(crate) model: Option<String>,
+    pub(crate) prompt: String,
+    pub(crate) max_tokens: Option<usize>,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) top_p: Option<f32>,
+    pub(crate) stop: Option<StopSequences>,
+    pub(crate) seed: Option<u32>,
+    #[serde(default)]
+    pub(crate) stream: bool,
+}
+
+/// OpenAI accepts either a single stop string or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StopSequences {
+    pub(crate) fn into_vec(self) -> Vec<String> {
+        match self {
+            StopSequences::One(stop) => vec![stop],
+            StopSequences::Many(stops) => stops,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct CompletionResponse {
+    pub(crate) id: String,
+    pub(crate) object: &'static str,
+    pub(crate) created: u64,
+    pub(crate) model: String,
+    pub(crate) choices: Vec<CompletionChoice>,
+    pub(crate) usage: Usage,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CompletionChoice {
+    pub(crate) text: String,
+    pub(crate) index: usize,
+    pub(crate) logprobs: Option<()>,
+    pub(crate) finish_reason: &'static str,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Usage {
+    pub(crate) prompt_tokens: usize,
+    pub(crate) completion_tokens: usize,
+    pub(crate) total_tokens: usize,
+}
+
+impl Usage {
+    pub(crate) fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+/// Body of `POST /v1/chat/completions`.
+#[derive(Deserialize)]
+pub(crate) struct ChatCompletionRequest {
+    pub(crate) model: Option<String>,
+    pub(crate) messages: Vec<ChatMessage>,
+    pub(crate) max_tokens: Option<usize>,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) top_p: Option<f32>,
+    pub(crate) stop: Option<StopSequences>,
+    pub(crate) seed: Option<u32>,
+    #[serde(default)]
+    pub(crate) stream: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ChatCompletionResponse {
+    pub(crate) id: String,
+    pub(crate) object: &'static str,
+    pub(crate) created: u64,
+    pub(crate) model: String,
+    pub(crate) choices: Vec<ChatCompletionChoice>,
+    pub(crate) usage: Usage,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ChatCompletionChoice {
+    pub(crate) index: usize,
+    pub(crate) message: ChatMessage,
+    pub(crate) finish_reason: &'static str,
+}
+
+/// A single Server-Sent Event of a streaming chat completion.
+#[derive(Serialize)]
+pub(crate) struct ChatCompletionChunk {
+    pub(crate) id: String,
+    pub(crate) object: &'static str,
+    pub(crate) created: u64,
+    pub(crate) model: String,
+    pub(crate) choices: Vec<ChatCompletionChunkChoice>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ChatCompletionChunkChoice {
+    pub(crate) index: usize,
+    pub(crate) delta: ChatDelta,
+    pub(crate) finish_reason: Option<&'static str>,
+}
+
+/// The part of the assistant message added by a chunk.
+#[derive(Serialize, Default)]
+pub(crate) struct ChatDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) role: Option<ChatRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content: Option<String>,
+}
+
+/// Body of `GET /v1/models`.
+#[derive(Serialize)]
+pub(crate) struct ModelList {
+    pub(crate) object: &'static str,
+    pub(crate) data: Vec<Model>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Model {
+    pub(crate) id: String,
+    pub(crate) object: &'static str,
+    pub(crate) created: u64,
+    pub(crate) owned_by: &'static str,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorResponse {
+    pub(crate) error: ErrorBody,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorBody {
+    pub(crate) message: String,
+    #[serde(rename = "type")]
+    pub(crate) error_type: &'static str,
+    pub(crate) code: Option<String>,
+}
@@ -0,0 +1,378 @@
+use rust_llm_server_common::OutputProcessorKind;
+
+/// A step of turning the text generated for a prompt into the lines handed out to the client. The
+/// output goes through it piece by piece while it is generated.
+pub(crate) trait OutputProcessor {
+    /// Take the next piece of output, returning the pieces it is done with.
+    fn process(&mut self, text: &str) -> Vec<String>;
+
+    /// Return whatever was held back, once the output is complete.
+    fn finish(&mut self) -> Vec<String>;
+}
+
+/// The processing of a prompt that doesn't ask for any: split the output into trimmed lines.
+pub(crate) fn default_output() -> Vec<OutputProcessorKind> {
+    vec![OutputProcessorKind::SplitLines]
+}
+
+/// The processors a prompt asked for, each of them taking what the one before it returned. What
+/// the last one returns are the lines of output.
+pub(crate) struct OutputPipeline {
+    processors: Vec<Box<dyn OutputProcessor>>,
+}
+
+impl OutputPipeline {
+    pub(crate) fn new(kinds: &[OutputProcessorKind]) -> Self {
+        let processors = kinds
+            .iter()
+            .map(|kind| -> Box<dyn OutputProcessor> {
+                match kind {
+                    OutputProcessorKind::Raw => Box::<RawOutput>::default(),
+                    OutputProcessorKind::SplitLines => Box::<LineSplitter>::default(),
+                    OutputProcessorKind::StripChars { chars } => Box::new(CharStripper { chars: chars.chars().collect() }),
+                    OutputProcessorKind::StripMarkdown => Box::<MarkdownStripper>::default(),
+                }
+            })
+            .collect();
+        Self { processors }
+    }
+
+    /// Feed the next piece of output through every processor, returning the lines it completed.
+    pub(crate) fn process(&mut self, text: &str) -> Vec<String> {
+        let mut pieces = vec![text.to_string()];
+        for processor in &mut self.processors {
+            pieces = pieces.iter().flat_map(|piece| processor.process(piece)).collect();
+        }
+        pieces
+    }
+
+    /// Flush every processor, each one after what the processors before it flushed, returning the last lines.
+    pub(crate) fn finish(&mut self) -> Vec<String> {
+        let mut pieces = Vec::new();
+        for processor in &mut self.processors {
+            let mut processed: Vec<String> = pieces.iter().flat_map(|piece: &String| processor.process(piece)).collect();
+            processed.extend(processor.finish());
+            pieces = processed;
+        }
+        pieces
+    }
+}
+
+/// Keeps the output as it is, handing it out in one piece once it is complete.
+#[derive(Default)]
+struct RawOutput {
+    text: String,
+}
+
+impl OutputProcessor for RawOutput {
+    fn process(&mut self, text: &str) -> Vec<String> {
+        self.text += text;
+        Vec::new()
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        vec![std::mem::take(&mut self.text)]
+    }
+}
+
+/// Hands out every line of the output once its newline comes, trimmed and without the newline.
+/// Lines that are empty once trimmed are left out.
+#[derive(Default)]
+struct LineSplitter {
+    line: String,
+}
+
+impl OutputProcessor for LineSplitter {
+    fn process(&mut self, text: &str) -> Vec<String> {
+        self.line += text;
+        let mut lines = Vec::new();
+        while let Some(end) = self.line.find('\n') {
+            let line: String = self.line.drain(..=end).collect();
+            lines.extend(non_empty_trimmed(&line));
+        }
+        lines
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        non_empty_trimmed(&std::mem::take(&mut self.line)).into_iter().collect()
+    }
+}
+
+fn non_empty_trimmed(line: &str) -> Option<String> {
+    let line = line.trim();
+    (!line.is_empty()).then(|| line.to_string())
+}
+
+/// Removes every occurrence of the given characters.
+struct CharStripper {
+    chars: Vec<char>,
+}
+
+impl OutputProcessor for CharStripper {
+    fn process(&mut self, text: &str) -> Vec<String> {
+        let stripped = text.replace(self.chars.as_slice(), "");
+        if stripped.is_empty() {
+            return Vec::new();
+        }
+        vec![stripped]
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Removes markdown syntax, one line at a time since the syntax depends on where the line starts.
+/// Code fences are dropped while the code between them is kept as it is.
+#[derive(Default)]
+struct MarkdownStripper {
+    line: String,
+    in_code_block: bool,
+}
+
+impl MarkdownStripper {
+    fn strip_line(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            self.in_code_block = !self.in_code_block;
+            return None;
+        }
+        if self.in_code_block {
+            return Some(line.to_string());
+        }
+
+        let indent = &line[..line.len() - trimmed.len()];
+        let content = trimmed.trim_end();
+        if is_horizontal_rule(content) {
+            return Some(String::new());
+        }
+
+        let heading = content.trim_start_matches('#');
+        let content = if heading.len() < content.len() && content.len() - heading.len() <= 6 && (heading.is_empty() || heading.starts_with(' ')) {
+            heading.trim_start()
+        } else if let Some(quote) = content.strip_prefix('>') {
+            quote.trim_start()
+        } else {
+            ["- ", "* ", "+ "].iter().find_map(|marker| content.strip_prefix(marker)).unwrap_or(content)
+        };
+        Some(format!("{}{}", indent, strip_inline_markdown(content)))
+    }
+}
+
+impl OutputProcessor for MarkdownStripper {
+    fn process(&mut self, text: &str) -> Vec<String> {
+        self.line += text;
+        let mut lines = Vec::new();
+        while let Some(end) = self.line.find('\n') {
+            let line: String = self.line.drain(..=end).collect();
+            if let Some(stripped) = self.strip_line(&line[..end]) {
+                lines.push(stripped + "\n");
+            }
+        }
+        lines
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        let line = std::mem::take(&mut self.line);
+        self.strip_line(&line).filter(|line| !line.is_empty()).into_iter().collect()
+    }
+}
+
+/// `---`, `***` or `___`, optionally with spaces in between.
+fn is_horizontal_rule(line: &str) -> bool {
+    let marks: Vec<char> = line.chars().filter(|c| *c != ' ').collect();
+    marks.len() >= 3 && ['-', '*', '_'].contains(&marks[0]) && marks.iter().all(|c| *c == marks[0])
+}
+
+/// Remove emphasis, strikethrough and inline code markers, and replace links and images with their text.
+/// The contents of inline code are kept as they are.
+fn strip_inline_markdown(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let before = text[..text.len() - rest.len()].chars().next_back();
+        if c == '`' {
+            let marker = &rest[..run_len(rest, c)];
+            if let Some(end) = rest[marker.len()..].find(marker) {
+                stripped += &rest[marker.len()..marker.len() + end];
+                rest = &rest[marker.len() + end + marker.len()..];
+                continue;
+            }
+        }
+        if c == '*' || c == '_' {
+            let marker = &rest[..run_len(rest, c)];
+            match split_emphasis(before, marker, &rest[marker.len()..]) {
+                Some((emphasized, after)) => {
+                    stripped += &strip_inline_markdown(emphasized);
+                    rest = after;
+                },
+                None => {
+                    stripped += marker;
+                    rest = &rest[marker.len()..];
+                },
+            }
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("~~") {
+            rest = after;
+            continue;
+        }
+
+        let label_start = if rest.starts_with("![") { 2 } else { 1 };
+        if c == '[' || label_start == 2 {
+            if let Some((label, after)) = split_link(&rest[label_start..]) {
+                stripped += &strip_inline_markdown(label);
+                rest = after;
+                continue;
+            }
+        }
+
+        stripped.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    stripped
+}
+
+/// Number of bytes `text` starts with that are the ASCII character `c`.
+fn run_len(text: &str, c: char) -> usize {
+    text.chars().take_while(|next| *next == c).count()
+}
+
+/// Split `emphasized<marker> rest`, which follows an opening `marker` of `*` or `_`, into the emphasized
+/// text and the rest. Emphasis can't start before or end after whitespace, and `_` doesn't emphasize
+/// within a word, so `snake_case` and `2 * 3` are kept. Neither is `_` taken for emphasis around a
+/// single word, which can't be told apart from names like `__init__`.
+fn split_emphasis<'a>(before: Option<char>, marker: &str, text: &'a str) -> Option<(&'a str, &'a str)> {
+    let underscore = marker.starts_with('_');
+    let opens = text.starts_with(|c: char| !c.is_whitespace()) && !(underscore && before.is_some_and(char::is_alphanumeric));
+    if !opens {
+        return None;
+    }
+
+    let mut search = 0;
+    while let Some(offset) = text[search..].find(marker) {
+        let start = search + offset;
+        let end = start + run_len(&text[start..], marker.chars().next().unwrap());
+        let emphasized = &text[..start];
+        let closes = end - start == marker.len()
+            && emphasized.ends_with(|c: char| !c.is_whitespace())
+            && !(underscore && text[end..].starts_with(char::is_alphanumeric));
+        if closes {
+            if underscore && emphasized.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return None;
+            }
+            return Some((emphasized, &text[end..]));
+        }
+        search = end;
+    }
+    None
+}
+
+/// Split `label](url) rest` into the label and the rest.
+fn split_link(text: &str) -> Option<(&str, &str)> {
+    let label_end = text.find("](")?;
+    let url_end = label_end + 2 + text[label_end + 2..].find(')')?;
+    Some((&text[..label_end], &text[url_end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(kinds: &[OutputProcessorKind], pieces: &[&str]) -> Vec<String> {
+        let mut pipeline = OutputPipeline::new(kinds);
+        let mut lines: Vec<String> = pieces.iter().flat_map(|piece| pipeline.process(piece)).collect();
+        lines.extend(pipeline.finish());
+        lines
+    }
+
+    fn markdown_lines(text: &str) -> Vec<String> {
+        output(&[OutputProcessorKind::StripMarkdown, OutputProcessorKind::SplitLines], &[text])
+    }
+
+    #[test]
+    fn output_is_the_same_however_it_is_split() {
+        let text = "# Größe\n\n```\nlet x = *y*;\n```\n- **fett** und [ein Link](https://example.com) ✓\nletzte Zeile #hashtag";
+        let pipelines = [
+            default_output(),
+            vec![OutputProcessorKind::Raw],
+            vec![OutputProcessorKind::StripChars { chars: "#*".to_string() }, OutputProcessorKind::SplitLines],
+            vec![OutputProcessorKind::StripMarkdown, OutputProcessorKind::SplitLines],
+            vec![OutputProcessorKind::StripMarkdown, OutputProcessorKind::Raw],
+        ];
+        for kinds in &pipelines {
+            let whole = output(kinds, &[text]);
+            for (split, _) in text.char_indices().skip(1) {
+                assert_eq!(output(kinds, &[&text[..split], &text[split..]]), whole, "{:?} split at {}", kinds, split);
+            }
+            let chars: Vec<String> = text.chars().map(String::from).collect();
+            let chars: Vec<&str> = chars.iter().map(String::as_str).collect();
+            assert_eq!(output(kinds, &chars), whole, "{:?} one char at a time", kinds);
+        }
+    }
+
+    #[test]
+    fn default_output_splits_trimmed_lines() {
+        assert_eq!(output(&default_output(), &["  # Title \n\n", "#hashtag\nlast"]), ["# Title", "#hashtag", "last"]);
+    }
+
+    #[test]
+    fn code_blocks_are_kept_without_their_fences() {
+        let text = "Before\n```rust\n    let x = **y**; // # not a heading\n```\n~~~\n[not](a link)\n~~~\nAfter **bold**";
+        assert_eq!(
+            output(&[OutputProcessorKind::StripMarkdown, OutputProcessorKind::Raw], &[text]),
+            ["Before\n    let x = **y**; // # not a heading\n[not](a link)\nAfter bold"]
+        );
+    }
+
+    #[test]
+    fn headings_are_stripped_but_hashtags_are_kept() {
+        assert_eq!(
+            markdown_lines("# One\n###### Six\n####### Seven\n#hashtag\n##\nText with #tag\n> quoted\n* item\n---"),
+            ["One", "Six", "####### Seven", "#hashtag", "Text with #tag", "quoted", "item"]
+        );
+    }
+
+    #[test]
+    fn links_and_images_are_replaced_with_their_text() {
+        assert_eq!(
+            markdown_lines("See [the **docs**](https://example.com/a_b) and ![a cat](cat.png).\n[no link] and [half](open"),
+            ["See the docs and a cat.", "[no link] and [half](open"]
+        );
+    }
+
+    #[test]
+    fn emphasis_is_stripped() {
+        assert_eq!(
+            markdown_lines("*one* _two words_ **three** __four five__ ***six*** ~~seven~~ `eight`\n*nested **bold** in italics*"),
+            ["one two words three four five six seven eight", "nested bold in italics"]
+        );
+    }
+
+    #[test]
+    fn identifiers_and_arithmetic_are_kept() {
+        assert_eq!(
+            markdown_lines("call __init__ and __main__ on snake_case_name\n2 * 3 * 4 and a_b * c\n`a*b*c` and `__init__`\n*unclosed and_"),
+            ["call __init__ and __main__ on snake_case_name", "2 * 3 * 4 and a_b * c", "a*b*c and __init__", "*unclosed and_"]
+        );
+    }
+
+    #[test]
+    fn finish_flushes_each_processor_in_order() {
+        // the line held back by the markdown stripper still goes through the processors after it
+        let kinds = [OutputProcessorKind::StripMarkdown, OutputProcessorKind::StripChars { chars: "!".to_string() }, OutputProcessorKind::SplitLines];
+        let mut pipeline = OutputPipeline::new(&kinds);
+        assert_eq!(pipeline.process("# first!\n**second**!"), ["first"]);
+        assert_eq!(pipeline.finish(), ["second"]);
+
+        // what an earlier processor flushes comes before what a later one held back
+        let mut pipeline = OutputPipeline::new(&[OutputProcessorKind::SplitLines, OutputProcessorKind::Raw]);
+        assert!(pipeline.process("one\ntwo\nthree").is_empty());
+        assert_eq!(pipeline.finish(), ["onetwothree"]);
+
+        let mut pipeline = OutputPipeline::new(&[OutputProcessorKind::Raw, OutputProcessorKind::SplitLines]);
+        assert!(pipeline.process("one\ntwo\n").is_empty());
+        assert!(pipeline.process("three").is_empty());
+        assert_eq!(pipeline.finish(), ["one", "two", "three"]);
+    }
+}
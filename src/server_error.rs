@@ -0,0 +1,39 @@
+use llama_cpp_rs::LError;
+use rust_llm_server_common::{ErrorCode, Message, RequestId};
+
+/// A failure that is reported back to the client that caused it.
+#[derive(Debug)]
+pub(crate) struct ServerError {
+    pub(crate) code: ErrorCode,
+    pub(crate) message: String,
+}
+
+impl ServerError {
+    pub(crate) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn into_message(self, request_id: Option<RequestId>) -> Message {
+        Message::Error {
+            request_id,
+            code: self.code,
+            message: self.message,
+        }
+    }
+}
+
+impl From<LError> for ServerError {
+    fn from(error: LError) -> Self {
+        let code = match error {
+            LError::InvalidCString(_) => ErrorCode::InvalidText,
+            LError::TokenizationError(_) => ErrorCode::TokenizationFailed,
+            LError::ApiError(_) | LError::CannotSampleBeforeInference | LError::NothingToContinue | LError::StateError(_) => ErrorCode::InferenceFailed,
+            LError::OutOfBufferSpace(_) => ErrorCode::ContextOverflow,
+            LError::ModelLoadError(_) => ErrorCode::ModelLoadFailed,
+        };
+        Self::new(code, error.to_string())
+    }
+}
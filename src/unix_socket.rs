@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Frames bigger than this are treated as a broken connection rather than allocated.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// A client that doesn't take a frame within this time is disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) enum UnixSocketEvent {
+    Accepted(u64),
+    Message(u64, Vec<u8>),
+    Disconnected(u64),
+}
+
+/// Queues of the frames to send to every connection accepted on the Unix domain socket. Each
+/// connection has its own writer thread, so a client that doesn't read never blocks the sender.
+#[derive(Default)]
+pub(crate) struct UnixStreams {
+    connections: Mutex<HashMap<u64, UnixConnection>>,
+    next_id: AtomicU64,
+}
+
+struct UnixConnection {
+    sender: Sender<Vec<u8>>,
+    /// Another handle of the socket, to shut it down while a write is blocked
+    control: UnixStream,
+}
+
+impl UnixStreams {
+    fn add(&self, stream: UnixStream) -> io::Result<u64> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let control = stream.try_clone()?;
+        let (sender, receiver) = mpsc::channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(id, UnixConnection { sender, control });
+        thread::spawn(move || write_frames(id, stream, receiver));
+        Ok(id)
+    }
+
+    /// Queue a single frame for a connection.
+    pub(crate) fn send(&self, id: u64, data: &[u8]) {
+        if let Some(connection) = self.connections.lock().unwrap().get(&id) {
+            let _ = connection.sender.send(data.to_vec());
+        }
+    }
+
+    /// Close a connection from our side, once what was sent to it before is written.
+    pub(crate) fn remove(&self, id: u64) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    /// Close a connection right away, dropping what wasn't written yet.
+    fn shut_down(&self, id: u64) {
+        if let Some(connection) = self.connections.lock().unwrap().remove(&id) {
+            let _ = connection.control.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Write the frames queued for a connection until it is removed. The socket is shut down afterwards, or
+/// when a write fails or times out, which makes its reader report the disconnect.
+fn write_frames(id: u64, mut stream: UnixStream, receiver: Receiver<Vec<u8>>) {
+    for data in receiver {
+        if let Err(err) = write_frame(&mut stream, &data) {
+            println!("unable to send to unix client {}: {}", id, err);
+            break;
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Listen for clients on a Unix domain socket at `path`, which is created with the permission bits `mode`.
+/// Frames use the same size prefix as message-io's `FramedTcp` transport, so clients only need to
+/// swap the transport to connect locally. `on_event` is called from the thread of each connection.
+pub(crate) fn listen_unix_socket(
+    path: &Path,
+    mode: u32,
+    streams: Arc<UnixStreams>,
+    on_event: impl Fn(UnixSocketEvent) + Send + Sync + 'static,
+) -> io::Result<()> {
+    // a socket file left behind by a previous run would make the bind fail, anything else is kept
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and isn't a socket", path.display()))),
+        Err(_) => {},
+    }
+    let listener = bind_with_mode(path, mode)?;
+
+    let on_event = Arc::new(on_event);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("unable to accept unix client: {}", err);
+                    continue;
+                },
+            };
+            let (id, reader) = match stream.try_clone().and_then(|reader| Ok((streams.add(stream)?, reader))) {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    println!("unable to accept unix client: {}", err);
+                    continue;
+                },
+            };
+
+            let streams = streams.clone();
+            let on_event = on_event.clone();
+            thread::spawn(move || {
+                on_event(UnixSocketEvent::Accepted(id));
+                let mut reader = BufReader::new(reader);
+                loop {
+                    match read_frame(&mut reader) {
+                        Ok(Some(data)) => on_event(UnixSocketEvent::Message(id, data)),
+                        Ok(None) => break,
+                        Err(err) => {
+                            println!("unix client {} sent a broken frame: {}", id, err);
+                            break;
+                        },
+                    }
+                }
+                streams.shut_down(id);
+                on_event(UnixSocketEvent::Disconnected(id));
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Bind the socket in a directory only we can access and link it to `path` once it has its permission
+/// bits, so that nobody can connect while the socket still has the permissions of the umask. Unlike a
+/// rename, the link fails rather than replace whatever is at `path` already.
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the socket path has no file name"))?;
+    let mut private_dir_name = file_name.to_os_string();
+    private_dir_name.push(format!(".{}.tmp", std::process::id()));
+    let private_dir = path.with_file_name(private_dir_name);
+    let private_path = private_dir.join(file_name);
+
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let result = UnixListener::bind(&private_path).and_then(|listener| {
+        fs::set_permissions(&private_path, Permissions::from_mode(mode))?;
+        fs::hard_link(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&private_path);
+    fs::remove_dir(&private_dir)?;
+    result
+}
+
+/// Write the size of the frame as an unsigned LEB128 varint, followed by the frame itself.
+fn write_frame(stream: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(data.len() + 10);
+    let mut size = data.len();
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        if size == 0 {
+            frame.push(byte);
+            break;
+        }
+        frame.push(byte | 0x80);
+    }
+    frame.extend_from_slice(data);
+    stream.write_all(&frame)
+}
+
+/// Read the next frame, `None` meaning the client closed the connection between two frames.
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut size = 0usize;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8];
+        match reader.read_exact(&mut byte) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            result => result?,
+        }
+        size |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= usize::BITS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame size doesn't fit into usize"));
+        }
+    }
+    if size > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too big", size)));
+    }
+
+    let mut data = vec![0u8; size];
+    reader.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out at most one byte per read, like a socket the frame trickles in through.
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl Read for OneByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut data = Vec::new();
+        let big_frame = vec![7u8; 300];
+        write_frame(&mut data, b"hello").unwrap();
+        write_frame(&mut data, b"").unwrap();
+        write_frame(&mut data, &big_frame).unwrap();
+        // 300 takes two bytes as a varint
+        assert_eq!(&data[7..9], &[0xac, 0x02]);
+
+        let mut reader = data.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(big_frame));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn frames_are_read_from_partial_reads() {
+        let mut data = Vec::new();
+        write_frame(&mut data, &[1u8; 200]).unwrap();
+        write_frame(&mut data, b"second").unwrap();
+
+        let mut reader = OneByteReader(&data);
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(vec![1u8; 200]));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut data = Vec::new();
+        write_frame(&mut data, b"hello").unwrap();
+
+        let err = read_frame(&mut &data[..3]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        // a size that is cut off after its first byte
+        let err = read_frame(&mut [0x80u8].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut data = Vec::new();
+        let mut len = MAX_FRAME_SIZE + 1;
+        while len >= 0x80 {
+            data.push((len & 0x7f) as u8 | 0x80);
+            len >>= 7;
+        }
+        data.push(len as u8);
+        let err = read_frame(&mut data.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a size with more bits than usize, which would overflow
+        let err = read_frame(&mut [0xffu8; 11].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn socket_gets_its_mode_before_it_is_visible() {
+        let dir = std::env::temp_dir().join(format!("llm-server-unix-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.sock");
+
+        let _listener = bind_with_mode(&path, 0o600).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        UnixStream::connect(&path).unwrap();
+        // only the socket is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn existing_files_are_kept() {
+        let dir = std::env::temp_dir().join(format!("llm-server-unix-test-existing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("not-a-socket");
+        fs::write(&file, "keep me").unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        for path in [&file, &link] {
+            let err = listen_unix_socket(path, 0o600, Arc::new(UnixStreams::default()), |_| {}).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+            // the link can't replace it either, should the file appear after the check
+            assert_eq!(bind_with_mode(path, 0o600).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep me");
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        // no private directory is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clients_that_dont_read_dont_block_sending() {
+        let (server_side, client) = UnixStream::pair().unwrap();
+        let streams = UnixStreams::default();
+        let id = streams.add(server_side).unwrap();
+
+        // far more than the socket buffer takes while the client isn't reading
+        let frame = vec![3u8; 1024 * 1024];
+        let start = std::time::Instant::now();
+        for _ in 0..32 {
+            streams.send(id, &frame);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // everything sent before the connection was removed still arrives, then it is closed
+        streams.remove(id);
+        let mut reader = BufReader::new(client);
+        for _ in 0..32 {
+            assert_eq!(read_frame(&mut reader).unwrap().as_deref(), Some(frame.as_slice()));
+        }
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tungstenite::{Message as WsMessage, WebSocket};
+
+/// How long a connection waits for a frame from its client before sending what was queued for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub(crate) enum WebSocketEvent {
+    Accepted(u64),
+    /// The payload of a text or binary message
+    Message(u64, Vec<u8>),
+    Disconnected(u64),
+}
+
+/// Queues of the messages to send to every connection accepted on the WebSocket listener. Each
+/// connection is served by its own thread, which owns the socket.
+#[derive(Default)]
+pub(crate) struct WebSockets {
+    senders: Mutex<HashMap<u64, Sender<WsMessage>>>,
+    next_id: AtomicU64,
+}
+
+impl WebSockets {
+    fn add(&self, sender: Sender<WsMessage>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.senders.lock().unwrap().insert(id, sender);
+        id
+    }
+
+    /// Send a single message to a connection, as a text frame if `is_text` and a binary frame otherwise.
+    pub(crate) fn send(&self, id: u64, data: &[u8], is_text: bool) {
+        let message = match is_text {
+            true => match String::from_utf8(data.to_vec()) {
+                Ok(text) => WsMessage::Text(text),
+                Err(err) => {
+                    println!("unable to send to websocket client {}: {}", id, err);
+                    return;
+                },
+            },
+            false => WsMessage::Binary(data.to_vec()),
+        };
+        if let Some(sender) = self.senders.lock().unwrap().get(&id) {
+            let _ = sender.send(message);
+        }
+    }
+
+    /// Close a connection from our side, once what was sent to it before is written.
+    pub(crate) fn remove(&self, id: u64) {
+        self.senders.lock().unwrap().remove(&id);
+    }
+}
+
+/// Listen for WebSocket clients on `addr`, returning the address the listener is bound to.
+/// Clients may send text or binary messages, and are answered in text frames while they use a
+/// text codec. `on_event` is called from the thread of each connection.
+pub(crate) fn listen_websocket(
+    addr: &str,
+    sockets: Arc<WebSockets>,
+    on_event: impl Fn(WebSocketEvent) + Send + Sync + 'static,
+) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+
+    let on_event = Arc::new(on_event);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("unable to accept websocket client: {}", err);
+                    continue;
+                },
+            };
+
+            let sockets = sockets.clone();
+            let on_event = on_event.clone();
+            thread::spawn(move || {
+                // the handshake is done before the read timeout is set, so a slow client can't break it
+                let mut socket = match tungstenite::accept(stream) {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        println!("websocket handshake failed: {}", err);
+                        return;
+                    },
+                };
+                if let Err(err) = socket.get_ref().set_read_timeout(Some(POLL_INTERVAL)) {
+                    println!("unable to accept websocket client: {}", err);
+                    return;
+                }
+
+                let (sender, receiver) = mpsc::channel();
+                let id = sockets.add(sender);
+                on_event(WebSocketEvent::Accepted(id));
+                if let Err(err) = serve_connection(&mut socket, &receiver, |data| on_event(WebSocketEvent::Message(id, data))) {
+                    println!("websocket client {} disconnected: {}", id, err);
+                }
+                sockets.remove(id);
+                on_event(WebSocketEvent::Disconnected(id));
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Alternate between writing the queued messages and waiting for the next frame of the client,
+/// until either side closes the connection.
+fn serve_connection(
+    socket: &mut WebSocket<TcpStream>,
+    receiver: &Receiver<WsMessage>,
+    on_message: impl Fn(Vec<u8>),
+) -> Result<(), Box<tungstenite::Error>> {
+    loop {
+        loop {
+            match receiver.try_recv() {
+                Ok(message) => socket.send(message)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    socket.close(None)?;
+                    socket.flush()?;
+                    return Ok(());
+                },
+            }
+        }
+
+        match socket.read() {
+            Ok(WsMessage::Text(text)) => on_message(text.into_bytes()),
+            Ok(WsMessage::Binary(data)) => on_message(data),
+            // pings are answered and closing is completed by tungstenite itself
+            Ok(_) => {},
+            Err(tungstenite::Error::Io(err)) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_frames_are_received_and_sent() {
+        let sockets = Arc::new(WebSockets::default());
+        let (events, received) = mpsc::channel();
+        let events = Mutex::new(events);
+        let addr = listen_websocket("127.0.0.1:0", sockets.clone(), move |event| {
+            events.lock().unwrap().send(event).unwrap();
+        })
+        .unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let (mut client, _) = tungstenite::client(format!("ws://{}/", addr), stream).unwrap();
+        let id = match received.recv_timeout(Duration::from_secs(5)).unwrap() {
+            WebSocketEvent::Accepted(id) => id,
+            _ => panic!("expected the connection to be accepted first"),
+        };
+
+        let hello = r#"{"Hello":{"protocol_version":11,"capabilities":[],"codec":"json"}}"#;
+        client.send(WsMessage::Text(hello.to_string())).unwrap();
+        match received.recv_timeout(Duration::from_secs(5)).unwrap() {
+            WebSocketEvent::Message(message_id, data) => {
+                assert_eq!(message_id, id);
+                assert_eq!(data, hello.as_bytes());
+            },
+            _ => panic!("expected the text frame to be received"),
+        }
+
+        sockets.send(id, b"{\"Welcome\":{}}", true);
+        assert_eq!(client.read().unwrap(), WsMessage::Text("{\"Welcome\":{}}".to_string()));
+        sockets.send(id, &[1, 2, 3], false);
+        assert_eq!(client.read().unwrap(), WsMessage::Binary(vec![1, 2, 3]));
+
+        sockets.remove(id);
+        assert!(matches!(client.read().unwrap(), WsMessage::Close(_)));
+        assert!(matches!(received.recv_timeout(Duration::from_secs(5)).unwrap(), WebSocketEvent::Disconnected(_)));
+    }
+}